@@ -23,7 +23,7 @@ use tk_easyloop::handle;
 
 fn main() {
     env_logger::init().expect("init logging");
-    let name = args().skip(1).next().unwrap_or("localhost:2003".to_string());
+    let name = args().nth(1).unwrap_or("localhost:2003".to_string());
     let (carbon, init) = Carbon::new(&Config::new().done());
     // run io in thread because stdin is not officially supported in
     // mio/tokio yet
@@ -26,6 +26,7 @@ fn main() {
             .map_err(|e| println!("Carbon error: {}", e))
             .and_then(|sock| {
                 init.from_connection(sock, &tk_easyloop::handle())
+                .map_err(|e| println!("Carbon error: {}", e))
             })
         })
     });
@@ -0,0 +1,542 @@
+use std::collections::{HashMap, BTreeMap};
+use std::fmt::Display;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Async};
+use num_traits::ToPrimitive;
+use tokio_core::reactor::{Handle, Timeout};
+
+use config::to_ms;
+use element::Metric;
+use error::Error;
+use public::Carbon;
+
+
+struct Shared {
+    counters: Mutex<HashMap<Vec<u8>, f64>>,
+    histograms: Mutex<Vec<Arc<HistogramData>>>,
+}
+
+/// Sums counters locally and submits them to carbon periodically
+///
+/// Every call to `incr` only updates a value in the hash map, and once per
+/// interval a single metric per name is submitted into `Carbon`. Also
+/// aggregator maintains histograms (see `histogram`). Intervals
+/// are aligned to the wall clock (i.e. with 10 second interval, metrics are
+/// sent at `:00`, `:10`, `:20` ...) and the timestamp of the metric is the
+/// end of the interval. Counters which were not incremented during the
+/// interval are not sent.
+///
+/// Aggregator may be cloned and used from any thread. When all clones
+/// (and histogram handles) are dropped, counters are flushed for the last
+/// time at the end of the current interval.
+///
+/// # Example
+///
+/// ```ignore
+/// let aggr = Aggregator::new(&carbon, Duration::from_secs(10), &handle);
+/// aggr.incr("my.requests", 1);
+/// ```
+#[derive(Clone)]
+pub struct Aggregator {
+    shared: Arc<Shared>,
+    carbon: Carbon,
+}
+
+/// A histogram handle
+///
+/// Created by
+/// [`Aggregator::histogram`](struct.Aggregator.html#method.histogram).
+/// Clones share the same histogram.
+#[derive(Clone)]
+pub struct Histogram {
+    /// Keeps flushing future running while histogram is used
+    _aggregator: Arc<Shared>,
+    data: Arc<HistogramData>,
+}
+
+struct HistogramData {
+    name: Vec<u8>,
+    tags: Vec<u8>,
+    percentiles: Vec<f64>,
+    precision: u32,
+    /// Logarithm of the ratio between bucket boundaries
+    gamma_ln: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    /// Number of values in a bucket by bucket index
+    counts: BTreeMap<i32, u64>,
+    /// Number of zero (and negative) values
+    zeros: u64,
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+struct Flusher {
+    shared: Arc<Shared>,
+    carbon: Carbon,
+    interval: u64,
+    handle: Handle,
+    /// End of the current interval in milliseconds since epoch
+    next: u64,
+    timeo: Timeout,
+}
+
+impl Aggregator {
+    /// Create an aggregator and spawn a flushing future in the loop
+    ///
+    /// # Panics
+    ///
+    /// Panics if interval is less than a millisecond.
+    pub fn new(carbon: &Carbon, interval: Duration, handle: &Handle)
+        -> Aggregator
+    {
+        let interval = to_ms(interval);
+        assert!(interval > 0);
+        let shared = Arc::new(Shared {
+            counters: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(Vec::new()),
+        });
+        let (next, delay) = next_boundary(interval, 0, now_ms());
+        handle.spawn(Flusher {
+            shared: shared.clone(),
+            carbon: carbon.clone(),
+            interval,
+            handle: handle.clone(),
+            next,
+            timeo: Timeout::new(delay, handle)
+                .expect("can always set a timeout"),
+        });
+        Aggregator { shared, carbon: carbon.clone() }
+    }
+
+    /// Add a value to the counter
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// aggr.incr("my.requests", 1);
+    /// aggr.incr(format_args!("requests.{}.bytes", path), body.len());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When name can't be formatted (Display'd)
+    /// * When formatted name contains a whitespace or a newline
+    /// * When `Carbon` has tags (see `Carbon::with_tags`) and name
+    ///   contains a semicolon
+    pub fn incr<N, V>(&self, name: N, value: V)
+        where N: Display, V: ToPrimitive
+    {
+        if let Err(e) = self.try_incr(name, value) {
+            panic!("Can't submit metric: {}", e);
+        }
+    }
+
+    /// Add a value to the counter
+    ///
+    /// This is a non-panicking version of [`incr`](#method.incr).
+    pub fn try_incr<N, V>(&self, name: N, value: V) -> Result<(), Error>
+        where N: Display, V: ToPrimitive
+    {
+        let mut buf = Vec::with_capacity(100);
+        self.carbon.encode_scoped_name(&mut buf, name)?;
+        let value = value.to_f64().unwrap_or(0.);
+        *lock(&self.shared.counters).entry(buf).or_insert(0.) += value;
+        Ok(())
+    }
+
+    /// Create a histogram
+    ///
+    /// At the end of each interval the following metrics are sent (if
+    /// there were any values recorded during the interval):
+    ///
+    /// * `<name>.count` -- number of values
+    /// * `<name>.mean` -- average value
+    /// * `<name>.max` -- maximum value
+    /// * `<name>.p<N>` -- for each of the `percentiles` (e.g. `p50`, `p99`,
+    ///   and `p99_9` for `99.9`)
+    ///
+    /// Values are stored in logarithmic buckets, so that percentiles have
+    /// at most `10^-precision` relative error (i.e. precision is a number
+    /// of significant decimal digits) regardless of the range of values.
+    /// Maximum and mean are exact. Negative values are treated as zero.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let hist = aggr.histogram("db.query", &[50., 90., 99.], 2);
+    /// hist.record_duration(start.elapsed());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When name can't be formatted (Display'd)
+    /// * When formatted name contains a whitespace or a newline
+    /// * When `Carbon` has tags (see `Carbon::with_tags`) and name
+    ///   contains a semicolon
+    /// * When percentile is not in range `(0, 100]`
+    /// * When precision is not in range `1..=5`
+    pub fn histogram<N: Display>(&self, name: N, percentiles: &[f64],
+        precision: u32)
+        -> Histogram
+    {
+        let mut buf = Vec::with_capacity(100);
+        if let Err(e) = self.carbon.encode_prefixed_name(&mut buf, name) {
+            panic!("Can't create histogram: {}", e);
+        }
+        assert!(percentiles.iter().all(|&p| p > 0. && p <= 100.));
+        assert!((1..=5).contains(&precision));
+        // part of the error is left for rounding of the reported value
+        let alpha = 0.9 * 10f64.powi(-(precision as i32));
+        let data = Arc::new(HistogramData {
+            name: buf,
+            tags: self.carbon.tags().to_vec(),
+            percentiles: percentiles.to_vec(),
+            precision,
+            gamma_ln: ((1. + alpha) / (1. - alpha)).ln(),
+            buckets: Mutex::new(Buckets::default()),
+        });
+        lock(&self.shared.histograms).push(data.clone());
+        Histogram {
+            _aggregator: self.shared.clone(),
+            data,
+        }
+    }
+}
+
+impl Histogram {
+    /// Record a value
+    pub fn record<V: ToPrimitive>(&self, value: V) {
+        let value = value.to_f64().unwrap_or(0.).max(0.);
+        let mut buckets = lock(&self.data.buckets);
+        if value == 0. {
+            buckets.zeros += 1;
+        } else {
+            let idx = (value.ln() / self.data.gamma_ln).ceil() as i32;
+            *buckets.counts.entry(idx).or_insert(0) += 1;
+        }
+        if buckets.count == 0 || value > buckets.max {
+            buckets.max = value;
+        }
+        buckets.count += 1;
+        buckets.sum += value;
+    }
+    /// Record a duration in milliseconds
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_secs() as f64 * 1000.
+            + duration.subsec_nanos() as f64 / 1_000_000.);
+    }
+}
+
+impl HistogramData {
+    /// Returns value of the percentile with relative error of `precision`
+    fn percentile(&self, buckets: &Buckets, percentile: f64) -> f64 {
+        // multiply first, so that e.g. 99.9% of 10000 isn't 9990.000001
+        let rank = ((percentile * buckets.count as f64 / 100.).ceil() as u64)
+            .max(1);
+        if rank <= buckets.zeros {
+            return 0.;
+        }
+        if rank >= buckets.count {
+            // the largest value is known exactly
+            return buckets.max;
+        }
+        let mut seen = buckets.zeros;
+        for (&idx, &num) in &buckets.counts {
+            seen += num;
+            if seen >= rank {
+                // the point which has the same relative error to both
+                // bucket boundaries
+                let gamma = self.gamma_ln.exp();
+                let value = 2. * (idx as f64 * self.gamma_ln).exp()
+                    / (gamma + 1.);
+                return round(value, self.precision + 2).min(buckets.max);
+            }
+        }
+        buckets.max
+    }
+}
+
+/// Rounds value to the number of significant decimal digits
+fn round(value: f64, digits: u32) -> f64 {
+    if value == 0. {
+        return 0.;
+    }
+    let scale = 10f64.powi(digits as i32 - 1 - value.log10().floor() as i32);
+    (value * scale).round() / scale
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn now_ms() -> u64 {
+    to_ms(SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("current time is after unix epoch"))
+}
+
+/// Returns end of the interval containing `now` and time till it
+///
+/// All values are in milliseconds since epoch. The interval ending at
+/// `prev` is skipped even if timer fired slightly earlier than wall clock
+/// reached it.
+fn next_boundary(interval: u64, prev: u64, now: u64) -> (u64, Duration) {
+    let next = ((now / interval + 1) * interval).max(prev + interval);
+    (next, Duration::from_millis(next - now))
+}
+
+impl Flusher {
+    fn flush(&mut self) {
+        let counters = mem::take(&mut *lock(&self.shared.counters));
+        let ts = UNIX_EPOCH + Duration::from_millis(self.next);
+        for (mut buf, value) in counters {
+            self.carbon.encode_value(&mut buf, value, ts)
+                .expect("timestamp is after unix epoch");
+            self.carbon.try_send(Metric(buf));
+        }
+        let mut histograms = lock(&self.shared.histograms);
+        for hist in histograms.iter() {
+            let buckets = mem::take(&mut *lock(&hist.buckets));
+            if buckets.count == 0 {
+                continue;
+            }
+            let mut values = vec![
+                ("count".to_string(), buckets.count as f64),
+                ("mean".to_string(), buckets.sum / buckets.count as f64),
+                ("max".to_string(), buckets.max),
+            ];
+            for &p in &hist.percentiles {
+                values.push((format!("p{}", p).replace('.', "_"),
+                             hist.percentile(&buckets, p)));
+            }
+            for (suffix, value) in values {
+                let mut buf = hist.name.clone();
+                buf.push(b'.');
+                buf.extend_from_slice(suffix.as_bytes());
+                buf.extend_from_slice(&hist.tags);
+                self.carbon.encode_value(&mut buf, value, ts)
+                    .expect("timestamp is after unix epoch");
+                self.carbon.try_send(Metric(buf));
+            }
+        }
+        // histogram handles are dropped
+        histograms.retain(|hist| Arc::strong_count(hist) > 1);
+    }
+}
+
+impl Future for Flusher {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Result<Async<()>, ()> {
+        while self.timeo.poll().expect("timeout never fails").is_ready() {
+            self.flush();
+            if Arc::strong_count(&self.shared) == 1 {
+                // all aggregators are dropped
+                return Ok(Async::Ready(()));
+            }
+            let (next, delay) = next_boundary(self.interval, self.next,
+                                              now_ms());
+            self.next = next;
+            self.timeo = Timeout::new(delay, &self.handle)
+                .expect("can always set a timeout");
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::executor;
+    use tokio_core::reactor::{Core, Timeout};
+
+    use {Carbon, Config, Init};
+    use super::{Aggregator, Flusher, Histogram, lock, round, next_boundary};
+
+    fn histogram(percentiles: &[f64], precision: u32) -> Histogram {
+        let core = Core::new().unwrap();
+        let (carbon, _init) = Carbon::new(&Config::new().done());
+        let aggr = Aggregator::new(&carbon, Duration::from_secs(10),
+                                   &core.handle());
+        aggr.histogram("test", percentiles, precision)
+    }
+
+    fn percentile(hist: &Histogram, percentile: f64) -> f64 {
+        hist.data.percentile(&lock(&hist.data.buckets), percentile)
+    }
+
+    fn assert_close(value: f64, expected: f64, precision: u32) {
+        let error = (value - expected).abs() / expected;
+        assert!(error <= 10f64.powi(-(precision as i32)),
+            "{} instead of {} (precision {})", value, expected, precision);
+    }
+
+    #[test]
+    fn relative_error() {
+        for precision in 1..6 {
+            let hist = histogram(&[50.], precision);
+            let mut value = 0.001;
+            while value < 1e7 {
+                *lock(&hist.data.buckets) = Default::default();
+                hist.record(value);
+                // so that estimate isn't capped by maximum
+                hist.record(1e12);
+                assert_close(percentile(&hist, 50.), value, precision);
+                value *= 1.0037;
+            }
+        }
+    }
+
+    #[test]
+    fn uniform() {
+        for precision in 1..6 {
+            let hist = histogram(&[50., 90., 99., 99.9], precision);
+            for value in 1..10001 {
+                hist.record(value);
+            }
+            assert_close(percentile(&hist, 50.), 5000., precision);
+            assert_close(percentile(&hist, 90.), 9000., precision);
+            assert_close(percentile(&hist, 99.), 9900., precision);
+            assert_close(percentile(&hist, 99.9), 9990., precision);
+            assert_close(percentile(&hist, 0.01), 1., precision);
+            // never exceeds maximum
+            assert_eq!(percentile(&hist, 100.), 10000.);
+        }
+    }
+
+    #[test]
+    fn exponential() {
+        let hist = histogram(&[], 3);
+        // 2^n is recorded 2^(20-n) times, so the median is 2
+        for n in 0..20 {
+            for _ in 0..1 << (20 - n) {
+                hist.record(1u64 << n);
+            }
+        }
+        assert_close(percentile(&hist, 40.), 1., 3);
+        assert_close(percentile(&hist, 60.), 2., 3);
+        assert_close(percentile(&hist, 90.), 8., 3);
+        assert_eq!(percentile(&hist, 100.), (1 << 19) as f64);
+    }
+
+    #[test]
+    fn zeros() {
+        let hist = histogram(&[], 2);
+        hist.record(0);
+        hist.record(-5);
+        hist.record(-0.1);
+        hist.record(10);
+        {
+            let buckets = lock(&hist.data.buckets);
+            assert_eq!(buckets.zeros, 3);
+            assert_eq!(buckets.count, 4);
+            assert_eq!(buckets.sum, 10.);
+            assert_eq!(buckets.max, 10.);
+        }
+        assert_eq!(percentile(&hist, 50.), 0.);
+        assert_eq!(percentile(&hist, 75.), 0.);
+        assert_eq!(percentile(&hist, 100.), 10.);
+
+        let hist = histogram(&[], 2);
+        hist.record(-1);
+        assert_eq!(lock(&hist.data.buckets).max, 0.);
+        assert_eq!(percentile(&hist, 99.), 0.);
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(round(123456., 3), 123000.);
+        assert_eq!(round(987.654, 4), 987.7);
+        assert_eq!(round(0.0012345, 2), 0.0012);
+        assert_eq!(round(0., 3), 0.);
+    }
+
+    #[test]
+    fn scoped_carbon() {
+        let core = Core::new().unwrap();
+        let (carbon, _init) = Carbon::new(&Config::new().done());
+        let scoped = carbon.with_prefix("p").unwrap()
+            .with_tags(vec![("k", "v")]).unwrap();
+        let aggr = Aggregator::new(&scoped, Duration::from_secs(10),
+                                   &core.handle());
+        aggr.incr("c", 1);
+        aggr.incr("c", 2);
+        assert_eq!(*lock(&aggr.shared.counters),
+                   vec![(b"p.c;k=v".to_vec(), 3.)].into_iter().collect());
+        let hist = aggr.histogram("h", &[50.], 2);
+        assert_eq!(hist.data.name, b"p.h");
+        assert_eq!(hist.data.tags, b";k=v");
+        assert!(aggr.try_incr("c;x=y", 1).is_err());
+    }
+
+    /// Returns sorted metrics buffered in the channel
+    fn received(init: Init) -> Vec<String> {
+        let count = init.chan.buffered_metrics();
+        let mut chan = executor::spawn(init.chan);
+        let mut lines = (0..count).map(|_| {
+            let metric = chan.wait_stream().unwrap().unwrap();
+            String::from_utf8(metric.0).unwrap()
+        }).collect::<Vec<_>>();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn boundaries() {
+        let interval = 10000;
+        let start = 1500000000000;
+        assert_eq!(next_boundary(interval, 0, start + 3000),
+                   (start + 10000, Duration::from_millis(7000)));
+        assert_eq!(next_boundary(interval, 0, start),
+                   (start + 10000, Duration::from_millis(10000)));
+        // timer fired slightly early, interval ending at `prev` is skipped
+        assert_eq!(next_boundary(interval, start + 10000, start + 9990),
+                   (start + 20000, Duration::from_millis(10010)));
+        assert_eq!(next_boundary(interval, start + 10000, start + 10000),
+                   (start + 20000, Duration::from_millis(10000)));
+        // timer fired late, skipped intervals are not flushed twice
+        assert_eq!(next_boundary(interval, start + 10000, start + 35000),
+                   (start + 40000, Duration::from_millis(5000)));
+    }
+
+    #[test]
+    fn flush_counters() {
+        let core = Core::new().unwrap();
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let aggr = Aggregator::new(&carbon, Duration::from_secs(10),
+                                   &core.handle());
+        let mut flusher = Flusher {
+            shared: aggr.shared.clone(),
+            carbon: carbon.clone(),
+            interval: 10000,
+            handle: core.handle(),
+            next: 1500000010000,
+            timeo: Timeout::new(Duration::from_secs(10), &core.handle())
+                .unwrap(),
+        };
+        aggr.incr("a", 1);
+        aggr.incr("b", 7);
+        aggr.incr("a", 2);
+        aggr.incr("a", 0.5);
+        flusher.flush();
+        flusher.next = 1500000020000;
+        // counters are reset after flush, idle ones are not sent
+        aggr.incr("b", 3);
+        flusher.flush();
+        flusher.next = 1500000030000;
+        flusher.flush();
+        assert_eq!(received(init), vec![
+            "a 3.5 1500000010\n",
+            "b 3 1500000020\n",
+            "b 7 1500000010\n",
+        ]);
+    }
+}
@@ -1,80 +1,550 @@
 //! The channel here is similar to `futures::sync::mpsc::channel` but allows
-//! non-blocking send (and looses message when buffer is full)
+//! non-blocking send (and looses message when buffer is full, according to
+//! the `Overflow` policy)
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::time::Instant;
 
-use futures::{Stream, Async};
-use futures::stream::{Fuse};
-use futures::sync::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
+use futures::{Stream, Async, AsyncSink, StartSend};
+use futures::task::{self, AtomicTask, Task};
 
 use element::{Metric};
+use error::Error;
+use config::Overflow;
+use {Config};
+
+/// Result of submitting a metric into the internal channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Metric is queued for sending
+    Submitted,
+    /// Metric is dropped because the internal buffer is full
+    BufferFull,
+    /// Metric is dropped because connection or pool is shut down
+    Disconnected,
+}
+
+struct Queue {
+    metrics: VecDeque<Metric>,
+    bytes: usize,
+    /// Tasks waiting for room in the buffer (via `Sink` interface)
+    waiters: Vec<Task>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    space: Condvar,
+    task: AtomicTask,
+    buffered: AtomicUsize,
+    bytes: AtomicUsize,
+    dropped: AtomicUsize,
+    /// Metrics received from the channel but not written to the network
+    in_flight: AtomicUsize,
+    senders: AtomicUsize,
+    receiver_gone: AtomicBool,
+    shutdown: AtomicBool,
+    /// Deadline for flushing metrics when shutdown is requested
+    shutdown_deadline: Mutex<Option<Instant>>,
+}
 
-#[derive(Clone)]
 pub struct Sender {
-    channel: UnboundedSender<Metric>,
-    buffered: Arc<AtomicUsize>,
+    shared: Arc<Shared>,
     max_metrics_buffered: usize,
+    max_bytes_buffered: usize,
+    overflow: Overflow,
 }
 
 pub struct Receiver {
-    channel: Fuse<UnboundedReceiver<Metric>>,
-    buffered: Arc<AtomicUsize>,
+    shared: Arc<Shared>,
+    max_metrics_buffered: usize,
+    max_bytes_buffered: usize,
+    done: bool,
+    /// Metrics dropped after shutdown is requested
+    lost: usize,
 }
 
-pub fn channel(max_metrics_buffered: usize) -> (Sender, Receiver) {
-    let (tx, rx) = unbounded();
-    let counter = Arc::new(AtomicUsize::new(0));
+/// Allows to shut down the channel without owning either side of it
+pub struct Control {
+    shared: Arc<Shared>,
+}
+
+pub fn channel(config: &Config) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            metrics: VecDeque::new(),
+            bytes: 0,
+            waiters: Vec::new(),
+        }),
+        space: Condvar::new(),
+        task: AtomicTask::new(),
+        buffered: AtomicUsize::new(0),
+        bytes: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        in_flight: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receiver_gone: AtomicBool::new(false),
+        shutdown: AtomicBool::new(false),
+        shutdown_deadline: Mutex::new(None),
+    });
     (Sender {
-        channel: tx,
-        buffered: counter.clone(),
-        max_metrics_buffered: max_metrics_buffered,
+        shared: shared.clone(),
+        max_metrics_buffered: config.max_metrics_buffered,
+        max_bytes_buffered: config.max_bytes_buffered,
+        overflow: config.overflow,
     }, Receiver {
-        channel: rx.fuse(),
-        buffered: counter.clone(),
+        shared,
+        max_metrics_buffered: config.max_metrics_buffered,
+        max_bytes_buffered: config.max_bytes_buffered,
+        done: false,
+        lost: 0,
     })
 }
 
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // metrics are plain buffers, so poisoned queue is still consistent
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn update_counters(&self, queue: &Queue) {
+        self.buffered.store(queue.metrics.len(), Ordering::Relaxed);
+        self.bytes.store(queue.bytes, Ordering::Relaxed);
+    }
+    /// Returns true if no more metrics can be sent into the channel
+    fn is_closed(&self) -> bool {
+        self.receiver_gone.load(Ordering::Relaxed) ||
+            self.shutdown.load(Ordering::Relaxed)
+    }
+}
+
+impl Queue {
+    fn push(&mut self, metric: Metric) {
+        self.bytes += metric.0.len();
+        self.metrics.push_back(metric);
+    }
+    fn pop(&mut self) -> Option<Metric> {
+        let metric = self.metrics.pop_front();
+        if let Some(ref m) = metric {
+            self.bytes -= m.0.len();
+        }
+        metric
+    }
+    fn wake_waiters(&mut self) {
+        for task in self.waiters.drain(..) {
+            task.notify();
+        }
+    }
+}
+
 impl Sender {
-    pub fn send(&self, metric: Metric) {
-        let max = self.max_metrics_buffered;
-        if self.buffered.load(Ordering::Relaxed) > max {
-            trace!("Warning can't send metric {}, buffer is full",
-                String::from_utf8_lossy(&metric.0));
-            return;
+    pub fn send(&self, metric: Metric) -> Status {
+        self.send_with(metric, self.overflow)
+    }
+    /// Same as `send` but never blocks
+    ///
+    /// `Overflow::Block` works as `Overflow::DropNewest`. This is used
+    /// from tasks running on the tokio loop.
+    pub fn try_send(&self, metric: Metric) -> Status {
+        let overflow = match self.overflow {
+            Overflow::Block(_) => Overflow::DropNewest,
+            overflow => overflow,
+        };
+        self.send_with(metric, overflow)
+    }
+    fn send_with(&self, metric: Metric, overflow: Overflow) -> Status {
+        let size = metric.0.len();
+        if self.shared.is_closed() {
+            debug!("Can't send metric, connection has been shut down");
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return Status::Disconnected;
+        }
+        if !self.fits(size) {
+            // will never fit in the buffer
+            return self.drop_newest(&metric);
+        }
+        if overflow == Overflow::DropNewest && self.is_full(
+            self.shared.buffered.load(Ordering::Relaxed),
+            self.shared.bytes.load(Ordering::Relaxed),
+            size)
+        {
+            return self.drop_newest(&metric);
+        }
+        let mut queue = self.shared.lock();
+        if self.is_full(queue.metrics.len(), queue.bytes, size) {
+            match overflow {
+                Overflow::DropNewest => {
+                    return self.drop_newest(&metric);
+                }
+                Overflow::DropOldest => {
+                    while self.is_full(queue.metrics.len(), queue.bytes, size)
+                    {
+                        let old = queue.pop()
+                            .expect("metric fits into an empty buffer");
+                        trace!("Buffer is full, dropping metric {}",
+                            String::from_utf8_lossy(&old.0));
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Overflow::Block(timeout) => {
+                    let deadline = Instant::now() + timeout;
+                    while self.is_full(queue.metrics.len(), queue.bytes, size)
+                    {
+                        let now = Instant::now();
+                        if now >= deadline || self.shared.is_closed() {
+                            drop(queue);
+                            return self.drop_newest(&metric);
+                        }
+                        queue = self.shared.space
+                            .wait_timeout(queue, deadline - now)
+                            .unwrap_or_else(|e| e.into_inner())
+                            .0;
+                    }
+                }
+            }
+        }
+        queue.push(metric);
+        self.shared.update_counters(&queue);
+        drop(queue);
+        self.shared.task.notify();
+        Status::Submitted
+    }
+    /// Send metric or park current task until there is room in the buffer
+    ///
+    /// Unlike `send` this ignores overflow policy.
+    pub fn start_send(&self, metric: Metric) -> StartSend<Metric, Error> {
+        if self.shared.is_closed() {
+            return Err(Error::Disconnected);
+        }
+        let size = metric.0.len();
+        if !self.fits(size) {
+            // will never fit in the buffer
+            return Err(Error::BufferFull);
         }
-        self.buffered.fetch_add(1, Ordering::Relaxed);
-        self.channel.unbounded_send(metric)
-        // This shouldn't happen actually
-        .map_err(|_| debug!("Can't send metric, \
-            connection has been shut down"))
-        // but we don't want it to be fatal
-        .ok();
+        let mut queue = self.shared.lock();
+        if self.is_full(queue.metrics.len(), queue.bytes, size) {
+            // task may poll several times before it's woken up
+            if !queue.waiters.iter().any(|t| t.will_notify_current()) {
+                queue.waiters.push(task::current());
+            }
+            return Ok(AsyncSink::NotReady(metric));
+        }
+        queue.push(metric);
+        self.shared.update_counters(&queue);
+        drop(queue);
+        self.shared.task.notify();
+        Ok(AsyncSink::Ready)
+    }
+    /// Returns false if metric doesn't fit even into an empty buffer
+    fn fits(&self, size: usize) -> bool {
+        self.max_metrics_buffered > 0 && size <= self.max_bytes_buffered
+    }
+    fn is_full(&self, metrics: usize, bytes: usize, size: usize) -> bool {
+        metrics >= self.max_metrics_buffered ||
+            bytes + size > self.max_bytes_buffered
+    }
+    fn drop_newest(&self, metric: &Metric) -> Status {
+        trace!("Warning can't send metric {}, buffer is full",
+            String::from_utf8_lossy(&metric.0));
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        Status::BufferFull
+    }
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
     }
     pub fn buffered(&self) -> (usize, usize) {
-        return (
-            self.buffered.load(Ordering::Relaxed),
+        (
+            self.shared.buffered.load(Ordering::Relaxed),
             self.max_metrics_buffered,
         )
     }
 }
 
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender {
+            shared: self.shared.clone(),
+            max_metrics_buffered: self.max_metrics_buffered,
+            max_bytes_buffered: self.max_bytes_buffered,
+            overflow: self.overflow,
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // last sender, wake up receiver so it can finish
+            self.shared.task.notify();
+        }
+    }
+}
+
+impl Receiver {
+    fn pop(&mut self) -> Option<Metric> {
+        let mut queue = self.shared.lock();
+        let metric = queue.pop();
+        if metric.is_some() {
+            self.shared.update_counters(&queue);
+            self.shared.space.notify_all();
+            queue.wake_waiters();
+        }
+        metric
+    }
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+    /// Account metrics dropped after they've been received from channel
+    pub fn count_dropped(&mut self, num: usize) {
+        self.shared.dropped.fetch_add(num, Ordering::Relaxed);
+        if self.shared.shutdown.load(Ordering::Relaxed) {
+            self.lost += num;
+        }
+    }
+    /// Put metric into the channel if there is room for it
+    ///
+    /// This is used for internal metrics, so they are processed the same
+    /// way as metrics submitted by user. If the channel is full the metric
+    /// is dropped regardless of the overflow policy, so internal metrics
+    /// never displace user metrics.
+    pub fn push(&mut self, metric: Metric) {
+        let mut queue = self.shared.lock();
+        if queue.metrics.len() >= self.max_metrics_buffered ||
+            queue.bytes + metric.0.len() > self.max_bytes_buffered
+        {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push(metric);
+        self.shared.update_counters(&queue);
+    }
+    /// Total number of metrics dropped
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+    /// Number of metrics buffered in the channel
+    pub fn buffered_metrics(&self) -> usize {
+        self.shared.buffered.load(Ordering::Relaxed)
+    }
+    /// Number of metrics dropped since shutdown has been requested
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+    /// Update number of metrics received but not written to the network
+    ///
+    /// These are lost if the connection future is dropped or fails.
+    pub fn set_in_flight(&self, num: usize) {
+        self.shared.in_flight.store(num, Ordering::Relaxed);
+    }
+    pub fn control(&self) -> Control {
+        Control { shared: self.shared.clone() }
+    }
+    /// Returns deadline of the shutdown if shutdown is requested
+    pub fn shutdown_deadline(&self) -> Option<Instant> {
+        *self.shared.shutdown_deadline.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+    /// Removes all metrics from the channel and counts them as dropped
+    pub fn discard(&mut self) -> usize {
+        let mut num = 0;
+        while self.pop().is_some() {
+            num += 1;
+        }
+        self.count_dropped(num);
+        num
+    }
+}
+
+impl Control {
+    /// Stop accepting metrics, the receiver finishes when channel is empty
+    pub fn shutdown(&self, deadline: Instant) {
+        *self.shared.shutdown_deadline.lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(deadline);
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        let mut queue = self.shared.lock();
+        self.shared.space.notify_all();
+        queue.wake_waiters();
+        drop(queue);
+        self.shared.task.notify();
+    }
+    /// Number of metrics still in the channel
+    pub fn buffered(&self) -> usize {
+        self.shared.buffered.load(Ordering::Relaxed)
+    }
+    /// Number of metrics received from the channel but not written yet
+    pub fn in_flight(&self) -> usize {
+        self.shared.in_flight.load(Ordering::Relaxed)
+    }
+}
+
 impl Stream for Receiver {
     type Item = Metric;
     type Error = ();   // Void
     fn poll(&mut self) -> Result<Async<Option<Metric>>, ()> {
-        match self.channel.poll() {
-            Ok(Async::Ready(Some(x))) => {
-                self.buffered.fetch_sub(1, Ordering::Relaxed);
-                Ok(Async::Ready(Some(x)))
-            }
-            y => y,
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(metric) = self.pop() {
+            return Ok(Async::Ready(Some(metric)));
         }
+        self.shared.task.register();
+        // senders are checked before the queue, so that metric sent just
+        // before dropping the last sender is not lost
+        let closed = self.shared.senders.load(Ordering::SeqCst) == 0 ||
+            self.shared.shutdown.load(Ordering::SeqCst);
+        // check again, to avoid race condition with registering task
+        if let Some(metric) = self.pop() {
+            return Ok(Async::Ready(Some(metric)));
+        }
+        if closed {
+            self.done = true;
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::NotReady)
     }
 }
 
-impl Receiver {
-    pub fn is_done(&self) -> bool {
-        self.channel.is_done()
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.receiver_gone.store(true, Ordering::SeqCst);
+        let mut queue = self.shared.lock();
+        self.shared.space.notify_all();
+        queue.wake_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::{Async, AsyncSink, Stream};
+    use futures::executor::{self, Notify};
+
+    use element::Metric;
+    use error::Error;
+    use config::Overflow;
+    use {Config};
+    use super::{channel, Receiver, Status};
+
+    fn metric() -> Metric {
+        Metric(b"a.b 1 1500000000\n".to_vec())
+    }
+
+    struct Wakeups(AtomicUsize);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _id: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn waiters(rx: &Receiver) -> usize {
+        rx.shared.lock().waiters.len()
+    }
+
+    #[test]
+    fn zero_limit() {
+        for &overflow in &[Overflow::DropNewest, Overflow::DropOldest,
+                           Overflow::Block(Duration::from_secs(10))]
+        {
+            let cfg = Config::new()
+                .max_metrics_buffered(0).overflow(overflow).done();
+            let (tx, _rx) = channel(&cfg);
+            assert_eq!(tx.send(metric()), Status::BufferFull);
+            match tx.start_send(metric()) {
+                Err(Error::BufferFull) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(tx.dropped(), 1);
+            assert_eq!(tx.buffered(), (0, 0));
+        }
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let cfg = Config::new()
+            .max_metrics_buffered(2).overflow(Overflow::DropOldest).done();
+        let (tx, _rx) = channel(&cfg);
+        for _ in 0..5 {
+            assert_eq!(tx.send(metric()), Status::Submitted);
+        }
+        assert_eq!(tx.dropped(), 3);
+        assert_eq!(tx.buffered(), (2, 2));
+    }
+
+    #[test]
+    fn try_send() {
+        let cfg = Config::new().max_metrics_buffered(1)
+            .overflow(Overflow::Block(Duration::from_secs(100))).done();
+        let (tx, _rx) = channel(&cfg);
+        assert_eq!(tx.try_send(metric()), Status::Submitted);
+        // doesn't wait for room in the buffer
+        assert_eq!(tx.try_send(metric()), Status::BufferFull);
+        assert_eq!(tx.dropped(), 1);
+
+        let cfg = Config::new().max_metrics_buffered(1)
+            .overflow(Overflow::DropOldest).done();
+        let (tx, _rx) = channel(&cfg);
+        assert_eq!(tx.try_send(metric()), Status::Submitted);
+        assert_eq!(tx.try_send(metric()), Status::Submitted);
+        assert_eq!(tx.dropped(), 1);
+    }
+
+    #[test]
+    fn push_limits() {
+        let cfg = Config::new()
+            .max_metrics_buffered(3).max_bytes_buffered(40)
+            .overflow(Overflow::DropOldest).done();
+        let (tx, mut rx) = channel(&cfg);
+        rx.push(metric());
+        assert_eq!(tx.send(metric()), Status::Submitted);
+        // bytes limit
+        rx.push(metric());
+        assert_eq!(rx.buffered_metrics(), 2);
+        assert_eq!(rx.dropped(), 1);
+
+        let cfg = Config::new().max_metrics_buffered(2).done();
+        let (tx, mut rx) = channel(&cfg);
+        assert_eq!(tx.send(metric()), Status::Submitted);
+        assert_eq!(tx.send(metric()), Status::Submitted);
+        // metrics limit
+        rx.push(metric());
+        assert_eq!(rx.buffered_metrics(), 2);
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[test]
+    fn start_send_wakeup() {
+        let cfg = Config::new().max_metrics_buffered(1).done();
+        let (tx, mut rx) = channel(&cfg);
+        let wakeups = Arc::new(Wakeups(AtomicUsize::new(0)));
+        let mut task = executor::spawn(tx);
+        let mut start_send = || task.poll_fn_notify(&wakeups, 0,
+            |tx| tx.start_send(metric()));
+        match start_send() {
+            Ok(AsyncSink::Ready) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        for _ in 0..3 {
+            match start_send() {
+                Ok(AsyncSink::NotReady(_)) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+        assert_eq!(waiters(&rx), 1);
+        assert_eq!(wakeups.0.load(Ordering::SeqCst), 0);
+        match rx.poll() {
+            Ok(Async::Ready(Some(_))) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(wakeups.0.load(Ordering::SeqCst), 1);
+        assert_eq!(waiters(&rx), 0);
+        match start_send() {
+            Ok(AsyncSink::Ready) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
     }
 }
@@ -1,10 +1,136 @@
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature="tls")] use native_tls::TlsConnector;
+
+use distribute::Distribute;
+use element::Protocol;
 use {Config};
 
+
+/// Jitter applied to exponential reconnect backoff
+///
+/// See [`Config::reconnect_backoff`](struct.Config.html#method.reconnect_backoff)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Jitter {
+    /// Delay is random between zero and the current backoff value
+    Full,
+    /// Delay is random between initial delay and previous delay multiplied
+    /// by the multiplier
+    Decorrelated,
+}
+
+/// Exponential backoff parameters (delays are in milliseconds)
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: u64,
+    pub max: u64,
+    pub multiplier: f64,
+    pub jitter: Jitter,
+}
+
+/// How metrics are distributed between hosts in the connection pool
+///
+/// See [`Config::distribution`](struct.Config.html#method.distribution).
+/// For other strategies implement [`Distribute`](trait.Distribute.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    /// Every metric is sent to every host (default)
+    Duplicate,
+    /// Every metric is sent to a single host chosen by consistent hashing
+    ///
+    /// The hashing algorithm is the same as `carbon_ch` in carbon-relay
+    /// (keyed by IP address of the host, as name is resolved before
+    /// hashing), so that metrics are sent directly to the carbon-cache
+    /// instance which stores them.
+    ///
+    /// Metrics whose host is not connected at the moment are kept in the
+    /// retry buffer of the host (see
+    /// [`Config::retry_buffer`](struct.Config.html#method.retry_buffer))
+    /// and are sent when connection is established.
+    ConsistentHash,
+    /// Every metric is sent to N hosts chosen by consistent hashing
+    ///
+    /// Hosts are chosen the same way as carbon-relay does with
+    /// `REPLICATION_FACTOR`. If any of the hosts is not connected, the
+    /// next host on the hashing ring is used instead, until the original
+    /// host is reconnected. So `Replicate(1)` is like `ConsistentHash` with
+    /// failover.
+    Replicate(usize),
+    /// Every metric is sent to the first connected host
+    ///
+    /// Hosts are tried in the order they are returned by the name
+    /// resolver. When primary host recovers, metrics are sent to it again.
+    Failover,
+}
+
+/// Policy applied when the internal channel is full
+///
+/// See [`Config::overflow`](struct.Config.html#method.overflow)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the metric being submitted (default)
+    DropNewest,
+    /// Evict the oldest metric in the buffer to make room for the new one
+    DropOldest,
+    /// Block the submitting thread until there is room in the buffer
+    ///
+    /// If there is still no room after the timeout, the metric being
+    /// submitted is dropped.
+    Block(Duration),
+}
+
+/// Precision of the timestamps of submitted metrics
+///
+/// See [`Config::timestamp`](struct.Config.html#method.timestamp)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Integer seconds since unix epoch (default)
+    Seconds,
+    /// Integer milliseconds since unix epoch
+    ///
+    /// With `Protocol::Pickle` works as `Fractional`.
+    Milliseconds,
+    /// Seconds since unix epoch with fractional part (microsecond
+    /// precision)
+    Fractional,
+}
+
+impl Timestamp {
+    /// Precision actually used for the protocol
+    ///
+    /// Pickle receivers always treat timestamps as seconds, so
+    /// milliseconds are written as fractional seconds instead.
+    pub(crate) fn for_protocol(self, protocol: Protocol) -> Timestamp {
+        match (self, protocol) {
+            (Timestamp::Milliseconds, Protocol::Pickle) => {
+                Timestamp::Fractional
+            }
+            (precision, _) => precision,
+        }
+    }
+    /// Write timestamp (duration since unix epoch) into the buffer
+    pub(crate) fn write(&self, buf: &mut Vec<u8>, tm: Duration) {
+        match *self {
+            Timestamp::Seconds => write!(buf, "{}", tm.as_secs()),
+            Timestamp::Milliseconds => write!(buf, "{}", to_ms(tm)),
+            Timestamp::Fractional => {
+                write!(buf, "{}.{:06}", tm.as_secs(), tm.subsec_micros())
+            }
+        }.expect("writing to buffer always succeed");
+    }
+}
+
 pub fn to_ms(dur: Duration) -> u64 {
-    return dur.as_secs() + (dur.subsec_nanos() / 1000000) as u64;
+    dur.as_secs() * 1000 + dur.subsec_millis() as u64
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
 }
 
 impl Config {
@@ -12,10 +138,27 @@ impl Config {
     pub fn new() -> Config {
         Config {
             write_timeout: Duration::new(10, 0),
+            connect_timeout: Duration::new(10, 0),
             watermarks: (60_000, 1_048_576),
+            retry_buffer: 1_048_576,
             max_metrics_buffered: 10000,
+            max_bytes_buffered: 10_485_760,
+            overflow: Overflow::DropNewest,
+            protocol: Protocol::Plaintext,
+            udp_mtu: 1400,
+            distribution: Arc::new(Distribution::Duplicate),
+            #[cfg(feature="tls")]
+            tls: None,
+            spool: None,
+            spool_segment_size: 16_777_216,
+            spool_replay_rate: 10000,
+            spool_delay: Duration::new(1, 0),
+            self_metrics: None,
+            timestamp: Timestamp::Seconds,
 
             reconnect_delay: (50, 150),
+            reconnect_backoff: None,
+            backoff_reset: Duration::new(60, 0),
         }
     }
 
@@ -51,6 +194,43 @@ impl Config {
         self
     }
 
+    /// Use exponential backoff with jitter for reconnects
+    ///
+    /// Each subsequent reconnect to the same address waits longer, starting
+    /// from `initial` delay and multiplying it by `multiplier` up to the
+    /// `max` delay. The actual delay is randomized according to the
+    /// `jitter`. Backoff is reset when connection stays healthy for
+    /// `backoff_reset` period.
+    ///
+    /// This overrides `reconnect_delay`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if initial delay is zero or larger than max delay, or if
+    /// multiplier is less than 1.
+    pub fn reconnect_backoff(&mut self, initial: Duration, max: Duration,
+        multiplier: f64, jitter: Jitter)
+        -> &mut Self
+    {
+        let (initial, max) = (to_ms(initial), to_ms(max));
+        assert!(initial > 0);
+        assert!(max >= initial);
+        assert!(multiplier >= 1.0);
+        self.reconnect_backoff = Some(Backoff {
+            initial, max, multiplier, jitter,
+        });
+        self
+    }
+
+    /// Period connection must be healthy to reset reconnect backoff
+    ///
+    /// Default is one minute. Only makes sense when `reconnect_backoff` is
+    /// set.
+    pub fn backoff_reset(&mut self, dur: Duration) -> &mut Self {
+        self.backoff_reset = dur;
+        self
+    }
+
     /// Timeout of writing at least some byte when there are any bytes in the
     /// outgoing buffer
     pub fn write_timeout(&mut self, dur: Duration) -> &mut Self {
@@ -58,6 +238,15 @@ impl Config {
         self
     }
 
+    /// Timeout of establishing connection (including TLS handshake)
+    ///
+    /// When connection is not established within this period, it's
+    /// considered failed and is retried after reconnect delay.
+    pub fn connect_timeout(&mut self, dur: Duration) -> &mut Self {
+        self.connect_timeout = dur;
+        self
+    }
+
     /// Buffer limits or watermarks
     ///
     /// The rules of thumb to not to loose any metrics:
@@ -94,6 +283,24 @@ impl Config {
         self
     }
 
+    /// Maximum bytes of unsent data kept when connection is dropped
+    ///
+    /// When a pool connection fails (or reaches high watermark) the data
+    /// which has not been written to the socket yet is kept and sent into
+    /// the next connection to the same address. Only whole metrics (or
+    /// whole pickle batches) are kept, and if there are more than this
+    /// limit the oldest ones are dropped. Default is 1 MiB, zero disables
+    /// retrying.
+    ///
+    /// With `Distribution::ConsistentHash` this buffer also keeps metrics
+    /// for the host which is not connected yet (or is reconnecting).
+    ///
+    /// Used only for connection pool (`Init::connect_to`).
+    pub fn retry_buffer(&mut self, bytes: usize) -> &mut Self {
+        self.retry_buffer = bytes;
+        self
+    }
+
     /// Maximum metrics buffered in a channel
     ///
     /// The rule of thumb: this channel should contain as much metrics as might
@@ -104,11 +311,216 @@ impl Config {
     /// This buffer is common for all the underlying channel between `Carbon`
     /// instance and the actual `Proto` or `Pool`. This channel is single
     /// one for all underlying connections.
+    ///
+    /// Zero limit drops every metric (and `Sink` returns `BufferFull`).
     pub fn max_metrics_buffered(&mut self, metrics: usize) -> &mut Self {
         self.max_metrics_buffered = metrics;
         self
     }
 
+    /// Maximum bytes of metrics buffered in a channel
+    ///
+    /// This is the same limit as `max_metrics_buffered` but counts total
+    /// size of the formatted metrics (including names and tags) rather than
+    /// their number. Metric is considered overflowing if either of the
+    /// limits is reached. Default is 10 MiB.
+    pub fn max_bytes_buffered(&mut self, bytes: usize) -> &mut Self {
+        self.max_bytes_buffered = bytes;
+        self
+    }
+
+    /// What to do when the channel is full
+    ///
+    /// Channel is full when either `max_metrics_buffered` or
+    /// `max_bytes_buffered` is reached.
+    ///
+    /// By default newest metrics are dropped (`Overflow::DropNewest`),
+    /// `Overflow::DropOldest` keeps the freshest data instead.
+    ///
+    /// `Overflow::Block` blocks the thread calling `add_value` until there
+    /// is room in the buffer or timeout expires. Never use it when metrics
+    /// are submitted from the same thread that runs the tokio loop which
+    /// is used for the connection, as this effectively stops
+    /// sending metrics for the duration of the timeout. `Aggregator`
+    /// flushes metrics from the tokio loop, so it drops newest metrics
+    /// instead of blocking.
+    pub fn overflow(&mut self, policy: Overflow) -> &mut Self {
+        self.overflow = policy;
+        self
+    }
+
+    /// Protocol used to send metrics
+    ///
+    /// Default is `Protocol::Plaintext`. Note, that pickle protocol is
+    /// usually served on a different port (2004 instead of 2003).
+    pub fn protocol(&mut self, protocol: Protocol) -> &mut Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// How metrics are distributed between hosts the name resolves to
+    ///
+    /// Default is `Distribution::Duplicate`, i.e. every metric is sent to
+    /// every host. Hashing ring is rebuilt when the set of addresses
+    /// changes.
+    ///
+    /// Either one of the `Distribution` variants or a custom strategy
+    /// implementing [`Distribute`](trait.Distribute.html) can be used.
+    ///
+    /// Used only for connection pool (`Init::connect_to`).
+    pub fn distribution<D>(&mut self, distribution: D) -> &mut Self
+        where D: Distribute + 'static,
+    {
+        self.distribution = Arc::new(distribution);
+        self
+    }
+
+    /// Precision of the timestamps
+    ///
+    /// Default is `Timestamp::Seconds`, which is the only format supported
+    /// by original carbon. Some carbon-compatible backends (go-carbon,
+    /// InfluxDB graphite plugin, VictoriaMetrics) also accept fractional or
+    /// millisecond timestamps, check your backend before changing this.
+    ///
+    /// Pickle protocol always sends seconds: fractional timestamps are sent
+    /// as floats, and `Timestamp::Milliseconds` works as
+    /// `Timestamp::Fractional`.
+    pub fn timestamp(&mut self, precision: Timestamp) -> &mut Self {
+        self.timestamp = precision;
+        self
+    }
+
+    /// Maximum size of the UDP datagram
+    ///
+    /// Used only for `Init::connect_udp`. Metrics are never split across
+    /// datagrams, so metric larger than this value is sent in its own
+    /// datagram. Default is 1400 bytes, which fits into ethernet frame
+    /// for both IPv4 and IPv6.
+    pub fn udp_mtu(&mut self, bytes: usize) -> &mut Self {
+        self.udp_mtu = bytes;
+        self
+    }
+
+    /// Use TLS for connections established by `Init::connect_to`
+    ///
+    /// Configure CA roots and client certificate using the
+    /// `native_tls::TlsConnector` builder. The `domain` is used for SNI and
+    /// is checked against the server certificate (it's required because
+    /// pool connects to resolved IP addresses).
+    ///
+    /// Unix socket connections are never wrapped into TLS.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let connector = TlsConnector::builder()
+    ///     .add_root_certificate(ca_cert)
+    ///     .identity(client_identity)
+    ///     .build()?;
+    /// let config = Config::new().tls(connector, "carbon.example.com").done();
+    /// ```
+    #[cfg(feature="tls")]
+    pub fn tls(&mut self, connector: TlsConnector, domain: &str)
+        -> &mut Self
+    {
+        self.tls = Some((connector, domain.to_string()));
+        self
+    }
+
+    /// Store metrics on disk when no host is connected
+    ///
+    /// When connection pool has no established connections for
+    /// `spool_delay`, metrics are pulled from the channel
+    /// and written into the segment files in the `dir` (created if doesn't
+    /// exist) instead of being dropped on channel overflow. When connection
+    /// is established again, the metrics are sent from the spool at the
+    /// rate of `spool_replay_rate`.
+    ///
+    /// Spool survives restart of the process: metrics left from the
+    /// previous run are replayed as soon as there is a connection. Only a
+    /// single process must use the directory at a time.
+    ///
+    /// When spool reaches `max_bytes` the oldest segment is dropped.
+    ///
+    /// Used only for connection pool (`Init::connect_to`).
+    pub fn spool<P: AsRef<Path>>(&mut self, dir: P, max_bytes: u64)
+        -> &mut Self
+    {
+        self.spool = Some((dir.as_ref().to_path_buf(), max_bytes));
+        self
+    }
+
+    /// Size of a single segment file of the spool
+    ///
+    /// Default is 16 MiB. Segment is the unit of dropping data when spool
+    /// is full, so it should be much smaller than the spool size.
+    pub fn spool_segment_size(&mut self, bytes: u64) -> &mut Self {
+        self.spool_segment_size = bytes;
+        self
+    }
+
+    /// Time without connections before metrics are written to the spool
+    ///
+    /// Default is 1 second. Until then metrics are kept in the channel, so
+    /// that short reconnects and startup of the process don't send metrics
+    /// through the spool (which delays them and breaks their order). Make
+    /// sure that `max_metrics_buffered` is enough for this period. Pending
+    /// connections don't postpone spooling, as connecting to an
+    /// unreachable host may take up to `connect_timeout`. On shutdown
+    /// metrics are written to the spool immediately.
+    pub fn spool_delay(&mut self, delay: Duration) -> &mut Self {
+        self.spool_delay = delay;
+        self
+    }
+
+    /// Maximum metrics per second replayed from the spool
+    ///
+    /// Default is 10000. Metrics are replayed only while connections keep
+    /// up with the traffic (are below low watermark), so this limit is
+    /// in addition to the normal backpressure.
+    pub fn spool_replay_rate(&mut self, metrics_per_second: usize)
+        -> &mut Self
+    {
+        self.spool_replay_rate = metrics_per_second;
+        self
+    }
+
+    /// Periodically send metrics about the client itself
+    ///
+    /// Connection pool submits the following metrics into the channel
+    /// every `interval` (so they are sent along with normal metrics):
+    ///
+    /// * `<prefix>.tk_carbon.sent` -- metrics written to the network during
+    ///   the interval (metric sent to several hosts is counted several
+    ///   times)
+    /// * `<prefix>.tk_carbon.dropped` -- metrics dropped during the interval
+    /// * `<prefix>.tk_carbon.reconnects` -- reconnects during the interval
+    /// * `<prefix>.tk_carbon.buffered_metrics` -- metrics in the channel
+    /// * `<prefix>.tk_carbon.buffered_bytes` -- bytes in connection buffers
+    /// * `<prefix>.tk_carbon.connections.<state>` -- number of connections
+    ///   in each state (`normal`, `crowded`, `pending`, `failed`,
+    ///   `retired`, see `ConnectionState`)
+    ///
+    /// If prefix is empty, names start with `tk_carbon.`.
+    ///
+    /// These metrics obey `max_metrics_buffered` and `max_bytes_buffered`
+    /// limits: when the channel is full they are dropped (and counted in
+    /// `dropped`) instead of evicting or blocking user metrics.
+    ///
+    /// Used only for connection pool (`Init::connect_to`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if prefix contains whitespace or interval is zero.
+    pub fn self_metrics(&mut self, prefix: &str, interval: Duration)
+        -> &mut Self
+    {
+        assert!(!prefix.contains(char::is_whitespace));
+        assert!(interval > Duration::new(0, 0));
+        self.self_metrics = Some((prefix.to_string(), interval));
+        self
+    }
+
     /// Create a Arc'd config clone to pass to the constructor
     ///
     /// This is just a convenience method.
@@ -0,0 +1,193 @@
+use std::fmt;
+
+use config::Distribution;
+use ring::Ring;
+
+
+/// Strategy of distributing metrics between hosts of the connection pool
+///
+/// [`Distribution`](enum.Distribution.html) covers the common cases.
+/// Implement this trait for a custom strategy and pass it to
+/// [`Config::distribution`](struct.Config.html#method.distribution).
+///
+/// # Example
+///
+/// ```ignore
+/// /// Sends every metric to two first connected hosts
+/// #[derive(Debug)]
+/// struct FirstTwo;
+///
+/// impl Distribute for FirstTwo {
+///     fn choose(&self, _name: &[u8], hosts: &Hosts,
+///         targets: &mut Vec<usize>)
+///     {
+///         targets.extend((0..hosts.len())
+///             .filter(|&idx| hosts.is_connected(idx))
+///             .take(2));
+///     }
+/// }
+/// ```
+pub trait Distribute: fmt::Debug + Send + Sync {
+    /// Fills `targets` with indexes of hosts the metric should be sent to
+    ///
+    /// `targets` is empty when method is called. Metric is dropped if
+    /// no hosts are chosen. Metrics chosen to be sent to a host which is
+    /// not connected at the moment are kept in the retry buffer of the host
+    /// (see [`Config::retry_buffer`](struct.Config.html#method.retry_buffer))
+    /// until connection is established. So to fail over to another host,
+    /// skip hosts which are not connected.
+    fn choose(&self, name: &[u8], hosts: &Hosts, targets: &mut Vec<usize>);
+}
+
+/// Hosts of the connection pool as seen by a `Distribute` strategy
+///
+/// Hosts are indexed in the order they are returned by the name resolver.
+pub struct Hosts<'a> {
+    ring: &'a Ring,
+    /// Index of the established connection for every host
+    connections: &'a [Option<usize>],
+}
+
+impl<'a> Hosts<'a> {
+    pub(crate) fn new(ring: &'a Ring, connections: &'a [Option<usize>])
+        -> Hosts<'a>
+    {
+        debug_assert_eq!(ring.nodes().len(), connections.len());
+        Hosts { ring, connections }
+    }
+    pub(crate) fn connection(&self, idx: usize) -> Option<usize> {
+        self.connections[idx]
+    }
+    /// Number of hosts the name resolves to
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+    /// Returns true if name resolves to no hosts
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+    /// Address of the host (e.g. `10.0.0.1:2003`)
+    pub fn address(&self, idx: usize) -> String {
+        self.ring.nodes()[idx].to_string()
+    }
+    /// Returns true if connection to the host is established and accepts
+    /// new metrics
+    pub fn is_connected(&self, idx: usize) -> bool {
+        self.connections[idx].is_some()
+    }
+    /// Host chosen for the metric by consistent hashing
+    ///
+    /// The hashing algorithm is the same as `carbon_ch` in carbon-relay.
+    pub fn hash_node(&self, name: &[u8]) -> Option<usize> {
+        self.ring.get_node(name)
+    }
+    /// All hosts in the order of preference by consistent hashing
+    ///
+    /// The first one is the same as returned by `hash_node`, first `N`
+    /// ones are the hosts carbon-relay chooses with `REPLICATION_FACTOR=N`.
+    pub fn hash_nodes(&self, name: &[u8]) -> Vec<usize> {
+        self.ring.get_nodes(name)
+    }
+}
+
+impl Distribute for Distribution {
+    fn choose(&self, name: &[u8], hosts: &Hosts, targets: &mut Vec<usize>) {
+        match *self {
+            Distribution::Duplicate => {
+                targets.extend((0..hosts.len())
+                    .filter(|&idx| hosts.is_connected(idx)));
+            }
+            Distribution::ConsistentHash => {
+                targets.extend(hosts.hash_node(name));
+            }
+            Distribution::Replicate(num) => {
+                targets.extend(hosts.hash_nodes(name).into_iter()
+                    .filter(|&idx| hosts.is_connected(idx))
+                    .take(num.max(1)));
+            }
+            Distribution::Failover => {
+                targets.extend((0..hosts.len())
+                    .find(|&idx| hosts.is_connected(idx)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Distribution;
+    use peer::Peer;
+    use ring::Ring;
+    use super::{Distribute, Hosts};
+
+    // hash order of the name is `[2, 1, 3, 4]` (see ring tests)
+    const NAME: &[u8] = b"carbon.agents.host1.cpuUsage";
+
+    fn ring() -> Ring {
+        Ring::new(&[
+            Peer::Tcp("10.0.0.1:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.2:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.3:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.4:2004".parse().unwrap()),
+        ])
+    }
+
+    /// Returns host addresses chosen when only `connected` hosts are up
+    fn choose(dist: Distribution, connected: &[usize]) -> Vec<String> {
+        let ring = ring();
+        let connections = (0..4)
+            .map(|idx| connected.iter().position(|&c| c == idx + 1))
+            .collect::<Vec<_>>();
+        let hosts = Hosts::new(&ring, &connections);
+        let mut targets = Vec::new();
+        dist.choose(NAME, &hosts, &mut targets);
+        targets.iter().map(|&idx| hosts.address(idx)).collect()
+    }
+
+    fn hosts(nums: &[usize]) -> Vec<String> {
+        nums.iter().map(|n| format!("10.0.0.{}:2004", n)).collect()
+    }
+
+    #[test]
+    fn duplicate() {
+        let dist = Distribution::Duplicate;
+        assert_eq!(choose(dist, &[1, 2, 3, 4]), hosts(&[1, 2, 3, 4]));
+        assert_eq!(choose(dist, &[4, 1]), hosts(&[1, 4]));
+        assert_eq!(choose(dist, &[]), hosts(&[]));
+    }
+
+    #[test]
+    fn consistent_hash() {
+        let dist = Distribution::ConsistentHash;
+        assert_eq!(choose(dist, &[1, 2, 3, 4]), hosts(&[2]));
+        // kept in the retry buffer of the host
+        assert_eq!(choose(dist, &[1, 3, 4]), hosts(&[2]));
+        assert_eq!(choose(dist, &[]), hosts(&[2]));
+    }
+
+    #[test]
+    fn replicate() {
+        let dist = Distribution::Replicate(2);
+        assert_eq!(choose(dist, &[1, 2, 3, 4]), hosts(&[2, 1]));
+        // failover to the next host on the ring
+        assert_eq!(choose(dist, &[2, 3, 4]), hosts(&[2, 3]));
+        assert_eq!(choose(dist, &[3]), hosts(&[3]));
+        // recovery
+        assert_eq!(choose(dist, &[1, 3, 4]), hosts(&[1, 3]));
+        assert_eq!(choose(dist, &[1, 2, 3]), hosts(&[2, 1]));
+        assert_eq!(choose(dist, &[]), hosts(&[]));
+        assert_eq!(choose(Distribution::Replicate(0), &[1, 2]), hosts(&[2]));
+    }
+
+    #[test]
+    fn failover() {
+        let dist = Distribution::Failover;
+        assert_eq!(choose(dist, &[1, 2, 3, 4]), hosts(&[1]));
+        assert_eq!(choose(dist, &[2, 3, 4]), hosts(&[2]));
+        assert_eq!(choose(dist, &[4]), hosts(&[4]));
+        // recovery
+        assert_eq!(choose(dist, &[3, 4]), hosts(&[3]));
+        assert_eq!(choose(dist, &[1, 3, 4]), hosts(&[1]));
+        assert_eq!(choose(dist, &[]), hosts(&[]));
+    }
+}
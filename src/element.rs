@@ -1,2 +1,165 @@
-/// An internal container for metric that is known to be valid
-pub struct Metric(pub Vec<u8>);
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+
+use tk_bufstream::Buf;
+
+use pickle;
+
+
+/// A formatted metric that is known to be valid
+///
+/// Use [`Carbon::metric`](struct.Carbon.html#method.metric) to create one.
+/// It's mostly useful for sending metrics via `Sink` interface.
+#[derive(Clone)]
+pub struct Metric(pub(crate) Vec<u8>);
+
+impl Metric {
+    /// Metric name including tags
+    pub(crate) fn name(&self) -> &[u8] {
+        let end = self.0.iter().position(|&x| x == b' ')
+            .unwrap_or(self.0.len());
+        &self.0[..end]
+    }
+}
+
+impl fmt::Debug for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Metric")
+            .field(&String::from_utf8_lossy(&self.0))
+            .finish()
+    }
+}
+
+/// Boundaries of the encoded batches in the output buffer
+///
+/// This allows to know how many metrics are in the buffer and which part
+/// of the buffer may be resent without breaking a line or pickle frame.
+#[derive(Debug, Default)]
+pub(crate) struct Chunks {
+    /// Size in bytes and number of metrics of each chunk
+    sizes: VecDeque<(usize, usize)>,
+    /// Number of bytes of the first chunk which are already written
+    written: usize,
+    /// Total number of metrics in `sizes`
+    metrics: usize,
+}
+
+impl Chunks {
+    /// Encode a batch of metrics into the output buffer as a new chunk
+    pub(crate) fn write_batch(&mut self, protocol: Protocol,
+        batch: &[Metric], buf: &mut Buf)
+    {
+        let old_len = buf.len();
+        protocol.encode(batch, buf);
+        self.sizes.push_back((buf.len() - old_len, batch.len()));
+        self.metrics += batch.len();
+    }
+    /// Removes chunks which are fully written
+    ///
+    /// Returns number of metrics in the removed chunks.
+    pub(crate) fn consumed(&mut self, bytes: usize) -> usize {
+        let mut bytes = self.written + bytes;
+        let mut metrics = 0;
+        while let Some(&(size, num)) = self.sizes.front() {
+            if bytes < size {
+                break;
+            }
+            bytes -= size;
+            metrics += num;
+            self.sizes.pop_front();
+        }
+        self.written = bytes;
+        self.metrics -= metrics;
+        metrics
+    }
+    /// Number of metrics which are not fully written yet
+    pub(crate) fn metrics(&self) -> usize {
+        self.metrics
+    }
+    /// Removes all chunks
+    ///
+    /// Returns the chunks and the number of already written bytes of the
+    /// first one.
+    pub(crate) fn take(&mut self) -> (VecDeque<(usize, usize)>, usize) {
+        self.metrics = 0;
+        (mem::take(&mut self.sizes), mem::replace(&mut self.written, 0))
+    }
+    /// Replaces chunks by the ones not written to the previous connection
+    pub(crate) fn restore(&mut self, sizes: VecDeque<(usize, usize)>) {
+        self.metrics = sizes.iter().map(|&(_, num)| num).sum();
+        self.sizes = sizes;
+        self.written = 0;
+    }
+}
+
+/// Protocol used to send metrics to carbon
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Plaintext line protocol (usually port 2003), default
+    Plaintext,
+    /// Pickle protocol (usually port 2004)
+    ///
+    /// This is more efficient for high volume of metrics, as metrics
+    /// are sent in batches.
+    Pickle,
+}
+
+impl Protocol {
+    /// Maximum number of metrics encoded at once
+    pub(crate) fn batch_size(&self) -> usize {
+        match *self {
+            Protocol::Plaintext => 1,
+            Protocol::Pickle => pickle::MAX_BATCH,
+        }
+    }
+    /// Encode a batch of metrics into the output buffer
+    pub(crate) fn encode(&self, metrics: &[Metric], buf: &mut Buf) {
+        match *self {
+            Protocol::Plaintext => {
+                for metric in metrics {
+                    buf.extend(&metric.0);
+                }
+            }
+            Protocol::Pickle => pickle::encode(metrics, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metric, Chunks};
+
+    #[test]
+    fn consumed() {
+        let mut chunks = Chunks::default();
+        chunks.restore(vec![(34, 2), (17, 1), (100, 5)].into());
+        assert_eq!(chunks.metrics(), 8);
+        assert_eq!(chunks.consumed(10), 0);
+        assert_eq!(chunks.written, 10);
+        assert_eq!(chunks.consumed(24), 2);
+        assert_eq!(chunks.written, 0);
+        assert_eq!(chunks.metrics(), 6);
+        // partially written chunk is still counted
+        assert_eq!(chunks.consumed(30), 1);
+        assert_eq!(chunks.written, 13);
+        assert_eq!(chunks.metrics(), 5);
+        assert_eq!(chunks.consumed(87), 5);
+        assert_eq!(chunks.written, 0);
+        assert!(chunks.sizes.is_empty());
+        assert_eq!(chunks.metrics(), 0);
+        assert_eq!(chunks.consumed(0), 0);
+
+        chunks.restore(vec![(34, 2), (17, 1)].into());
+        assert_eq!(chunks.consumed(20), 0);
+        assert_eq!(chunks.take(), (vec![(34, 2), (17, 1)].into(), 20));
+        assert_eq!(chunks.metrics(), 0);
+        assert_eq!(chunks.written, 0);
+    }
+
+    #[test]
+    fn debug() {
+        assert_eq!(format!("{:?}", Metric(b"a.b 1 1500000000\n".to_vec())),
+                   r#"Metric("a.b 1 1500000000\n")"#);
+    }
+}
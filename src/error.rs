@@ -0,0 +1,80 @@
+use std::io;
+use std::time::SystemTimeError;
+
+
+quick_error! {
+    /// Error when metric can't be submitted
+    #[derive(Debug)]
+    pub enum Error {
+        /// Metric name contains characters not allowed in carbon protocol
+        ///
+        /// Semicolon is also not allowed in the name if tags are added
+        /// by `Carbon::with_tags`, or in the prefix.
+        InvalidName(name: String) {
+            description("invalid metric name")
+            display("invalid metric name {:?}", name)
+        }
+        /// Tag name is empty or contains one of the `;!^=` or whitespace
+        InvalidTagName(name: String) {
+            description("invalid tag name")
+            display("invalid tag name {:?}", name)
+        }
+        /// Tag value is empty, starts with `~` or contains `;` or whitespace
+        InvalidTagValue(value: String) {
+            description("invalid tag value")
+            display("invalid tag value {:?}", value)
+        }
+        /// Formatted value is empty or contains whitespace
+        InvalidValue(value: String) {
+            description("invalid metric value")
+            display("invalid metric value {:?}", value)
+        }
+        /// Timestamp is smaller than UNIX_EPOCH
+        BadTimestamp(err: SystemTimeError) {
+            description("timestamp is before unix epoch")
+            display("timestamp is before unix epoch: {}", err)
+            cause(err)
+        }
+        /// Metric is dropped because internal buffer is full
+        BufferFull {
+            description("metrics buffer is full")
+        }
+        /// Metric is dropped because connection or pool is shut down
+        Disconnected {
+            description("carbon connection is shut down")
+        }
+    }
+}
+
+quick_error! {
+    /// Error of the connection returned by `Proto` future
+    #[derive(Debug)]
+    pub enum ProtoError {
+        /// Error reading from the connection
+        Read(err: io::Error) {
+            description("error reading from carbon connection")
+            display("error reading from carbon connection: {}", err)
+            cause(err)
+        }
+        /// Server has sent some data, which is not expected by protocol
+        UnexpectedInput {
+            description("unexpected input from carbon server")
+        }
+        /// No bytes could be written within `Config::write_timeout`
+        WriteTimeout {
+            description("timeout writing to carbon connection")
+        }
+        /// Error writing to the connection
+        Write(err: io::Error) {
+            description("error writing to carbon connection")
+            display("error writing to carbon connection: {}", err)
+            cause(err)
+        }
+        /// Error setting a timer in the event loop
+        Timer(err: io::Error) {
+            description("error setting timer")
+            display("error setting timer: {}", err)
+            cause(err)
+        }
+    }
+}
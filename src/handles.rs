@@ -0,0 +1,231 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use num_traits::Num;
+
+use channel::Status;
+use element::Metric;
+use public::Carbon;
+
+
+/// A counter handle
+///
+/// Created by [`Carbon::counter`](struct.Carbon.html#method.counter).
+/// Every increment sends the total value of the counter since the handle
+/// was created, so increments within the same second are not lost when
+/// carbon overwrites the value. Use `nonNegativeDerivative()` or
+/// `perSecond()` in graphite to get the rate.
+///
+/// Clones share the same total.
+#[derive(Debug, Clone)]
+pub struct Counter {
+    carbon: Carbon,
+    name: Arc<Vec<u8>>,
+    total: Arc<AtomicU64>,
+}
+
+/// A gauge handle
+///
+/// Created by [`Carbon::gauge`](struct.Carbon.html#method.gauge).
+#[derive(Debug, Clone)]
+pub struct Gauge {
+    carbon: Carbon,
+    name: Arc<Vec<u8>>,
+}
+
+/// A timer handle
+///
+/// Created by [`Carbon::timer`](struct.Carbon.html#method.timer).
+/// Durations are sent in milliseconds (with fractional part).
+#[derive(Debug, Clone)]
+pub struct Timer {
+    carbon: Carbon,
+    name: Arc<Vec<u8>>,
+}
+
+/// Records time elapsed since `Timer::start` when dropped
+#[derive(Debug)]
+pub struct TimerGuard<'a> {
+    timer: &'a Timer,
+    start: Instant,
+}
+
+fn name<N: Display>(carbon: &Carbon, name: N) -> Arc<Vec<u8>> {
+    let mut buf = Vec::with_capacity(100);
+    if let Err(e) = carbon.encode_scoped_name(&mut buf, name) {
+        panic!("Can't create metric handle: {}", e);
+    }
+    Arc::new(buf)
+}
+
+fn send<V>(carbon: &Carbon, name: &[u8], value: V) -> Status
+    where V: Num + Display
+{
+    let mut buf = Vec::with_capacity(name.len() + 32);
+    buf.extend_from_slice(name);
+    if let Err(e) = carbon.encode_value(&mut buf, value, SystemTime::now()) {
+        panic!("Can't submit metric: {}", e);
+    }
+    carbon.send(Metric(buf))
+}
+
+impl Counter {
+    pub(crate) fn new<N: Display>(carbon: &Carbon, name: N) -> Counter {
+        Counter {
+            carbon: carbon.clone(),
+            name: self::name(carbon, name),
+            total: Arc::new(AtomicU64::new(0)),
+        }
+    }
+    /// Increment the counter and send new total value
+    pub fn incr(&self, value: u64) -> Status {
+        let total = self.total.fetch_add(value, Ordering::Relaxed)
+            .wrapping_add(value);
+        send(&self.carbon, &self.name, total)
+    }
+    /// Current total value of the counter
+    pub fn value(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge {
+    pub(crate) fn new<N: Display>(carbon: &Carbon, name: N) -> Gauge {
+        Gauge {
+            carbon: carbon.clone(),
+            name: self::name(carbon, name),
+        }
+    }
+    /// Send current value of the gauge
+    ///
+    /// # Panics
+    ///
+    /// When value can't be formatted (Display'd)
+    pub fn set<V: Num + Display>(&self, value: V) -> Status {
+        send(&self.carbon, &self.name, value)
+    }
+}
+
+impl Timer {
+    pub(crate) fn new<N: Display>(carbon: &Carbon, name: N) -> Timer {
+        Timer {
+            carbon: carbon.clone(),
+            name: self::name(carbon, name),
+        }
+    }
+    /// Send a duration
+    pub fn record(&self, duration: Duration) -> Status {
+        let ms = duration.as_secs() as f64 * 1000.
+            + duration.subsec_nanos() as f64 / 1_000_000.;
+        send(&self.carbon, &self.name, ms)
+    }
+    /// Start measuring time, which is recorded when guard is dropped
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let timer = carbon.timer("db.query");
+    /// {
+    ///     let _guard = timer.start();
+    ///     run_query();
+    /// }
+    /// ```
+    pub fn start(&self) -> TimerGuard<'_> {
+        TimerGuard {
+            timer: self,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<'a> Drop for TimerGuard<'a> {
+    fn drop(&mut self) {
+        self.timer.record(self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use futures::executor;
+
+    use {Carbon, Config, Init};
+
+    /// Returns metrics buffered in the channel as `(name, value)` pairs
+    fn received(init: Init) -> Vec<(String, f64)> {
+        let count = init.chan.buffered_metrics();
+        let mut chan = executor::spawn(init.chan);
+        (0..count).map(|_| {
+            let metric = chan.wait_stream().unwrap().unwrap();
+            let line = String::from_utf8(metric.0).unwrap();
+            let mut parts = line.split(' ');
+            let name = parts.next().unwrap().to_string();
+            (name, parts.next().unwrap().parse().unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn counter_totals() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let counter = carbon.counter("requests");
+        let clone = counter.clone();
+        counter.incr(1);
+        clone.incr(5);
+        counter.incr(0);
+        assert_eq!(counter.value(), 6);
+        assert_eq!(clone.value(), 6);
+        assert_eq!(received(init), vec![
+            ("requests".to_string(), 1.),
+            ("requests".to_string(), 6.),
+            ("requests".to_string(), 6.),
+        ]);
+        // a new handle has its own total
+        assert_eq!(carbon.counter("requests").value(), 0);
+    }
+
+    #[test]
+    fn timer_guard() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let timer = carbon.timer("query");
+        {
+            let _guard = timer.start();
+            sleep(Duration::from_millis(20));
+            assert_eq!(init.chan.buffered_metrics(), 0);
+        }
+        let metrics = received(init);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].0, "query");
+        assert!(metrics[0].1 >= 20. && metrics[0].1 < 10000.,
+            "{}", metrics[0].1);
+    }
+
+    #[test]
+    fn scoped_names() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let scoped = carbon.with_prefix("app").unwrap()
+            .with_tags(vec![("host", "a")]).unwrap();
+        let counter = scoped.counter("hits");
+        let gauge = scoped.gauge("load");
+        let timer = scoped.timer(format_args!("{}.time", "db"));
+        assert_eq!(*counter.name, b"app.hits;host=a");
+        assert_eq!(*gauge.name, b"app.load;host=a");
+        assert_eq!(*timer.name, b"app.db.time;host=a");
+        // encoded name is shared by clones, not encoded again
+        assert!(Arc::ptr_eq(&counter.name, &counter.clone().name));
+        assert!(Arc::ptr_eq(&gauge.name, &gauge.clone().name));
+        assert!(Arc::ptr_eq(&timer.name, &timer.clone().name));
+        counter.incr(2);
+        gauge.set(7);
+        timer.record(Duration::from_millis(1500));
+        assert_eq!(received(init), vec![
+            ("app.hits;host=a".to_string(), 2.),
+            ("app.load;host=a".to_string(), 7.),
+            ("app.db.time;host=a".to_string(), 1500.),
+        ]);
+    }
+}
@@ -32,12 +32,53 @@
 //!
 //! let (carbon, init) = Carbon::new(&Config::new().done());
 //! handle.spawn(TcpStream::connect(&addr, &handle)
-//!     .and_then(move |sock| init.from_connection(sock, &handle2))
-//!     .map_err(|e| unimplemented!()));
+//!     .map_err(|e| error!("Can't connect: {}", e))
+//!     .and_then(move |sock| init.from_connection(sock, &handle2)
+//!         // `ProtoError` tells why connection is closed
+//!         .map_err(|e| error!("Carbon error: {}", e))));
 //! // use carbon the same way as above
 //! carbon.add_metric("my.metric", 10);
 //! ```
 //!
+//! # UDP
+//!
+//! Metrics may also be sent over UDP (plaintext protocol only), which never
+//! blocks on slow or unavailable carbon:
+//!
+//! ```rust,ignore
+//! let (carbon, init) = Carbon::new(&Config::new().done());
+//! init.connect_udp(resolver.subscribe("localhost:2003"), &handle);
+//! ```
+//!
+//! # TLS
+//!
+//! When `tls` feature is enabled, connection pool can wrap each connection
+//! into TLS (see [`Config::tls`](struct.Config.html#method.tls)).
+//! Low level interface (`Init::from_connection`) accepts any stream, so you
+//! can wrap the connection yourself.
+//!
+//! # Tagged Metrics
+//!
+//! Graphite 1.1 and later supports tags on metrics. Use
+//! [`add_tagged_value`](struct.Carbon.html#method.add_tagged_value) to
+//! submit them:
+//!
+//! ```rust,ignore
+//! carbon.add_tagged_value("my.metric", vec![("host", "web1")], 10)?;
+//! ```
+//!
+//! # Shutdown
+//!
+//! To make sure buffered metrics are sent before exiting the process, use
+//! the handle returned by `connect_to` (or `Proto::shutdown_handle`):
+//!
+//! ```rust,ignore
+//! let shutdown = init.connect_to(resolver.subscribe("localhost:2003"),
+//!                                &handle);
+//! // ... on exit
+//! let lost = core.run(shutdown.shutdown(Duration::from_secs(5)))?;
+//! ```
+//!
 //! # General
 //!
 //! [`Carbon`](struct.Carbon.html) object is the same for connection pool and
@@ -57,9 +98,14 @@ extern crate tokio_core;
 extern crate tokio_io;
 extern crate tk_bufstream;
 extern crate rand;
+extern crate md5;
 extern crate void;
+#[cfg(unix)] extern crate tokio_uds;
+#[cfg(feature="tls")] extern crate native_tls;
+#[cfg(feature="tls")] extern crate tokio_tls;
 
 #[macro_use] extern crate log;
+#[macro_use] extern crate quick_error;
 
 mod public;
 mod element;
@@ -67,10 +113,31 @@ mod proto;
 mod pool;
 mod config;
 mod channel;
+mod error;
+mod pickle;
+mod udp;
+mod peer;
+mod ring;
+mod distribute;
+mod spool;
+mod shutdown;
+mod stats;
+mod aggregate;
+mod handles;
 
 pub use public::Carbon;
 pub use proto::Proto;
+pub use aggregate::{Aggregator, Histogram};
+pub use handles::{Counter, Gauge, Timer, TimerGuard};
+pub use error::{Error, ProtoError};
+pub use channel::Status;
+pub use config::{Overflow, Jitter, Distribution, Timestamp};
+pub use distribute::{Distribute, Hosts};
+pub use element::{Metric, Protocol};
+pub use shutdown::{Shutdown, ShutdownFuture};
+pub use stats::{Stats, ConnectionStats, ConnectionState};
 
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -78,6 +145,7 @@ use std::time::Duration;
 pub struct Init {
     chan: channel::Receiver,
     config: Arc<Config>,
+    stats: Stats,
 }
 
 /// Configuration of carbon protocol
@@ -86,9 +154,28 @@ pub struct Init {
 #[derive(Clone, Debug)]
 pub struct Config {
     write_timeout: Duration,
+    connect_timeout: Duration,
     watermarks: (usize, usize),
+    retry_buffer: usize,
     max_metrics_buffered: usize,
+    max_bytes_buffered: usize,
+    overflow: Overflow,
+    protocol: Protocol,
+    udp_mtu: usize,
+    distribution: Arc<dyn Distribute>,
+    #[cfg(feature="tls")]
+    tls: Option<(native_tls::TlsConnector, String)>,
+    /// Spool directory and its maximum size in bytes
+    spool: Option<(PathBuf, u64)>,
+    spool_segment_size: u64,
+    spool_replay_rate: usize,
+    spool_delay: Duration,
+    /// Prefix and interval of the metrics about the client itself
+    self_metrics: Option<(String, Duration)>,
+    timestamp: Timestamp,
 
     /// Reconnect delay in milliseconds, so it's easier to generate random
     reconnect_delay: (u64, u64),
+    reconnect_backoff: Option<config::Backoff>,
+    backoff_reset: Duration,
 }
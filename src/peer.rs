@@ -0,0 +1,136 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)] use std::path::PathBuf;
+
+use futures::{Future, Poll};
+#[cfg(unix)] use futures::future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)] use tokio_uds::UnixStream;
+#[cfg(feature="tls")] use tokio_tls::{TlsConnector, TlsStream};
+
+use {Config};
+
+
+/// Address of a single carbon backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Connection to a single carbon backend
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature="tls")]
+    Tls(TlsStream<TcpStream>),
+}
+
+pub type ConnectFuture = Box<dyn Future<Item=Connection, Error=io::Error>>;
+
+
+impl Peer {
+    pub fn connect(&self, config: &Config, handle: &Handle)
+        -> ConnectFuture
+    {
+        match *self {
+            Peer::Tcp(ref addr) => tcp_connect(addr, config, handle),
+            #[cfg(unix)]
+            Peer::Unix(ref path) => {
+                // connect on unix sockets either fails or succeeds
+                // immediately
+                Box::new(future::result(UnixStream::connect(path, handle)
+                    .map(Connection::Unix)))
+            }
+        }
+    }
+}
+
+#[cfg(not(feature="tls"))]
+fn tcp_connect(addr: &SocketAddr, _config: &Config, handle: &Handle)
+    -> ConnectFuture
+{
+    Box::new(TcpStream::connect(addr, handle).map(Connection::Tcp))
+}
+
+#[cfg(feature="tls")]
+fn tcp_connect(addr: &SocketAddr, config: &Config, handle: &Handle)
+    -> ConnectFuture
+{
+    let conn = TcpStream::connect(addr, handle);
+    match config.tls {
+        Some((ref connector, ref domain)) => {
+            let connector = TlsConnector::from(connector.clone());
+            let domain = domain.clone();
+            Box::new(conn
+                .and_then(move |sock| {
+                    connector.connect(&domain, sock)
+                    .map_err(io::Error::other)
+                })
+                .map(Connection::Tls))
+        }
+        None => Box::new(conn.map(Connection::Tcp)),
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Tcp(ref addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Peer::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(ref mut s) => s.read(buf),
+            #[cfg(feature="tls")]
+            Connection::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(ref mut s) => s.write(buf),
+            #[cfg(feature="tls")]
+            Connection::Tls(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(ref mut s) => s.flush(),
+            #[cfg(feature="tls")]
+            Connection::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Connection::Tcp(ref mut s) => AsyncWrite::shutdown(s),
+            #[cfg(unix)]
+            Connection::Unix(ref mut s) => AsyncWrite::shutdown(s),
+            #[cfg(feature="tls")]
+            Connection::Tls(ref mut s) => AsyncWrite::shutdown(s),
+        }
+    }
+}
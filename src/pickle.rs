@@ -0,0 +1,137 @@
+//! Encoder for the carbon pickle protocol
+//!
+//! Each message is a 4-byte big-endian length followed by pickled list of
+//! `(path, (timestamp, value))` tuples. We use pickle protocol 2, which is
+//! understood by carbon running on both python 2 and python 3.
+
+use std::str::from_utf8;
+
+use tk_bufstream::Buf;
+
+use element::Metric;
+
+
+/// Maximum number of metrics in a single pickle message
+pub const MAX_BATCH: usize = 500;
+
+const PROTO: u8 = 0x80;
+const EMPTY_LIST: u8 = b']';
+const MARK: u8 = b'(';
+const BINUNICODE: u8 = b'X';
+const BININT: u8 = b'J';
+const BINFLOAT: u8 = b'G';
+const TUPLE2: u8 = 0x86;
+const APPENDS: u8 = b'e';
+const STOP: u8 = b'.';
+
+
+/// Encodes a batch of metrics as a single length-prefixed pickle message
+pub fn encode(metrics: &[Metric], buf: &mut Buf) {
+    let mut data = Vec::with_capacity(metrics.len() * 64);
+    data.extend(&[PROTO, 2, EMPTY_LIST, MARK]);
+    for metric in metrics {
+        let (name, ts, value) = match parse(&metric.0) {
+            Some(x) => x,
+            None => {
+                warn!("Can't encode metric {:?} in pickle format",
+                    String::from_utf8_lossy(&metric.0));
+                continue;
+            }
+        };
+        data.push(BINUNICODE);
+        data.extend(&(name.len() as u32).to_le_bytes());
+        data.extend(name.as_bytes());
+        if ts.fract() == 0.0 && ts <= i32::MAX as f64 {
+            data.push(BININT);
+            data.extend(&(ts as i32).to_le_bytes());
+        } else {
+            data.push(BINFLOAT);
+            data.extend(&ts.to_bits().to_be_bytes());
+        }
+        data.push(BINFLOAT);
+        data.extend(&value.to_bits().to_be_bytes());
+        data.push(TUPLE2);
+        data.push(TUPLE2);
+    }
+    data.extend(&[APPENDS, STOP]);
+    buf.extend(&(data.len() as u32).to_be_bytes());
+    buf.extend(&data);
+}
+
+/// Splits a plaintext line `name value timestamp\n` into parts
+fn parse(line: &[u8]) -> Option<(&str, f64, f64)> {
+    let line = from_utf8(line).ok()?;
+    let mut parts = line.trim_end().rsplitn(3, ' ');
+    let ts = parts.next()?.parse().ok()?;
+    let value = parts.next()?.parse().ok()?;
+    let name = parts.next()?;
+    Some((name, ts, value))
+}
+
+#[cfg(test)]
+mod test {
+    use tk_bufstream::Buf;
+
+    use element::Metric;
+    use super::{encode, parse};
+
+    fn encoded(lines: &[&str]) -> Vec<u8> {
+        let metrics = lines.iter()
+            .map(|x| Metric(x.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        let mut buf = Buf::new();
+        encode(&metrics, &mut buf);
+        buf[..].to_vec()
+    }
+
+    #[test]
+    fn parse_line() {
+        assert_eq!(parse(b"a.b 1 1500000000\n"),
+                   Some(("a.b", 1500000000., 1.)));
+        assert_eq!(parse(b"a;k=v -2.5 1500000000.5\n"),
+                   Some(("a;k=v", 1500000000.5, -2.5)));
+        assert_eq!(parse(b"a.b 1\n"), None);
+        assert_eq!(parse(b"a.b x 1500000000\n"), None);
+        assert_eq!(parse(b"a.b 1 x\n"), None);
+        assert_eq!(parse(b"a.\xff 1 1500000000\n"), None);
+    }
+
+    #[test]
+    fn encode_batch() {
+        // decoded by python: [('a.b', (1500000000, 1.0)),
+        //   ('c;k=v', (1500000000.5, -2.5)), ('e.f', (3000000000.0, 3.0))]
+        let expected: &[&[u8]] = &[
+            b"\x00\x00\x00X",  // length
+            b"\x80\x02](",  // PROTO 2, EMPTY_LIST, MARK
+            // integer timestamp fits BININT
+            b"X\x03\x00\x00\x00a.b",
+            b"J\x00/hY",
+            b"G?\xf0\x00\x00\x00\x00\x00\x00",
+            b"\x86\x86",
+            // fractional timestamp is BINFLOAT
+            b"X\x05\x00\x00\x00c;k=v",
+            b"GA\xd6Z\x0b\xc0 \x00\x00",
+            b"G\xc0\x04\x00\x00\x00\x00\x00\x00",
+            b"\x86\x86",
+            // timestamp larger than i32::MAX is BINFLOAT
+            b"X\x03\x00\x00\x00e.f",
+            b"GA\xe6Z\x0b\xc0\x00\x00\x00",
+            b"G@\x08\x00\x00\x00\x00\x00\x00",
+            b"\x86\x86",
+            b"e.",  // APPENDS, STOP
+        ];
+        assert_eq!(encoded(&[
+            "a.b 1 1500000000\n",
+            "c;k=v -2.5 1500000000.5\n",
+            "e.f 3 3000000000\n",
+        ]), expected.concat());
+    }
+
+    #[test]
+    fn skip_invalid() {
+        assert_eq!(encoded(&["bad line\n"]),
+                   b"\x00\x00\x00\x06\x80\x02](e.");
+        assert_eq!(encoded(&["bad line\n", "a.b 1 1500000000\n"]),
+                   encoded(&["a.b 1 1500000000\n"]));
+    }
+}
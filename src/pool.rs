@@ -1,19 +1,28 @@
-use std::collections::VecDeque;
+use std::collections::{VecDeque, HashMap};
 use std::io;
-use std::net::SocketAddr;
+#[cfg(unix)] use std::path::Path;
 use std::sync::Arc;
-use std::time::{Instant, Duration};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 
 use abstract_ns::Address;
 use futures::{Future, Async, Stream};
+use futures::sync::oneshot;
+#[cfg(unix)] use futures::{future, stream};
 use rand::{thread_rng, Rng};
-use tk_bufstream::IoBuf;
+use tk_bufstream::{IoBuf, Buf};
 use tokio_io::{AsyncWrite};
-use tokio_core::net::TcpStream;
 use tokio_core::reactor::{Handle, Timeout};
 use void::{Void, unreachable};
 
 use channel::Receiver;
+use config::Jitter;
+use distribute::Hosts;
+use element::{Metric, Chunks, Protocol};
+use peer::{Peer, Connection};
+use ring::Ring;
+use shutdown::Shutdown;
+use spool::Spool;
+use stats::{Stats, Entry, ConnectionState};
 use {Init, Config};
 
 
@@ -25,18 +34,61 @@ struct Pool<A> {
     deadline: Instant,
     timeo: Timeout,
 
-    cur_address: Option<Address>,
-    normal: VecDeque<(SocketAddr, Conn<TcpStream>)>,
-    crowded: VecDeque<(SocketAddr, Conn<TcpStream>)>,
-    pending: VecDeque<(SocketAddr,
-                       Box<Future<Item=Conn<TcpStream>, Error=io::Error>>)>,
-    retired: VecDeque<Conn<TcpStream>>,
-    failed: VecDeque<(SocketAddr, Instant)>,
+    cur_addresses: Option<Vec<Peer>>,
+    ring: Ring,
+    normal: VecDeque<(Peer, Conn<Connection>)>,
+    crowded: VecDeque<(Peer, Conn<Connection>)>,
+    /// Pending connections with connect deadline and backoff state
+    pending: VecDeque<(Peer, Instant, u64, PendingConn)>,
+    retired: VecDeque<(Peer, Conn<Connection>)>,
+    /// Failed connections with reconnect time and backoff state
+    failed: VecDeque<(Peer, Instant, u64)>,
+    /// Unsent data of failed connections to send into the next connection
+    retry: HashMap<Peer, Unsent>,
+    spool: Option<Spool>,
+    next_replay: Instant,
+    /// Time since when no connection is established
+    disconnected: Option<Instant>,
+    /// Notified with the number of lost metrics when pool is finished
+    finished: Option<oneshot::Sender<usize>>,
+    stats: Stats,
+    reconnects: HashMap<Peer, u64>,
+    report: Report,
 }
 
+/// Counters for metrics about the pool itself (see `Config::self_metrics`)
+struct Report {
+    next: Instant,
+    sent: usize,
+    reconnects: usize,
+    /// Total number of metrics dropped at the time of the previous report
+    dropped: usize,
+}
+
+/// Spool is replayed in small portions every this number of milliseconds
+const REPLAY_INTERVAL: u64 = 100;
+
+type PendingConn = Box<dyn Future<Item=Conn<Connection>, Error=io::Error>>;
+
 struct Conn<T> {
     io: IoBuf<T>,
     deadline: Instant,
+    connected: Instant,
+    /// Backoff state when connection was established (see `next_delay`)
+    backoff: u64,
+    chunks: Chunks,
+}
+
+/// Whole chunks of data which were not written to the failed connection
+///
+/// Also metrics held back for the host which is not connected (see
+/// `Pool::hold_back`).
+#[derive(Default)]
+struct Unsent {
+    data: Vec<u8>,
+    chunks: VecDeque<(usize, usize)>,
+    /// Total number of metrics in `chunks`
+    metrics: usize,
 }
 
 
@@ -46,29 +98,90 @@ impl Init {
     /// This method spawns a future (or futures) in the loop represented
     /// by handle. The future exits when all references to API (`Carbon`
     /// structure) are dropped and all buffers are flushed.
+    ///
+    /// Returned handle may be used to shut down the pool gracefully.
     pub fn connect_to<S>(self, address_stream: S, handle: &Handle)
+        -> Shutdown
         where S: Stream<Item=Address, Error=Void> + 'static,
     {
-        handle.spawn(Pool {
-            address_stream: address_stream,
-            channel: self.chan,
+        self.spawn_pool(address_stream.map(|addr| {
+            addr.at(0).addresses().map(Peer::Tcp).collect()
+        }), handle)
+    }
+
+    /// Establishes connection to a unix socket
+    ///
+    /// This works exactly like `connect_to` (i.e. reconnects, respects
+    /// watermarks and write timeout), but connects to a single unix
+    /// socket at the specified path. This is useful for sending metrics
+    /// to a local relay (e.g. carbon-c-relay).
+    #[cfg(unix)]
+    pub fn connect_to_unix<P: AsRef<Path>>(self, path: P, handle: &Handle)
+        -> Shutdown
+    {
+        let path = path.as_ref().to_path_buf();
+        self.spawn_pool(stream::once(Ok(vec![Peer::Unix(path)]))
+            // address never changes, but we don't want pool to shut down
+            .chain(future::empty().into_stream()), handle)
+    }
+
+    fn spawn_pool<S>(self, address_stream: S, handle: &Handle) -> Shutdown
+        where S: Stream<Item=Vec<Peer>, Error=Void> + 'static,
+    {
+        let (pool, shutdown) = Pool::new(self, address_stream, handle);
+        handle.spawn(pool);
+        shutdown
+    }
+}
+
+impl<S> Pool<S> {
+    fn new(init: Init, address_stream: S, handle: &Handle)
+        -> (Pool<S>, Shutdown)
+    {
+        let (shutdown, finished) = Shutdown::new(init.chan.control());
+        let spool = init.config.spool.as_ref().and_then(|&(ref dir, max)| {
+            Spool::open(dir, max, init.config.spool_segment_size)
+            .map_err(|e| error!("Can't open spool {:?}: {}", dir, e))
+            .ok()
+        });
+        let report_time = Instant::now() + init.config.self_metrics.as_ref()
+            .map(|&(_, interval)| interval)
+            .unwrap_or_else(|| Duration::new(86400, 0));
+        let pool = Pool {
+            address_stream,
+            channel: init.chan,
             handle: handle.clone(),
-            deadline: Instant::now() + self.config.write_timeout,
-            timeo: Timeout::new(self.config.write_timeout, &handle)
+            deadline: Instant::now() + init.config.write_timeout,
+            timeo: Timeout::new(init.config.write_timeout, handle)
                 .expect("can always set a timeout"),
-            config: self.config,
+            config: init.config,
 
-            cur_address: None,
+            cur_addresses: None,
+            ring: Ring::new(&[]),
             normal: VecDeque::new(),
             crowded: VecDeque::new(),
             pending: VecDeque::new(),
             retired: VecDeque::new(),
             failed: VecDeque::new(),
-        });
+            retry: HashMap::new(),
+            spool,
+            next_replay: Instant::now(),
+            disconnected: Some(Instant::now()),
+            finished: Some(finished),
+            stats: init.stats,
+            reconnects: HashMap::new(),
+            report: Report {
+                next: report_time,
+                sent: 0,
+                reconnects: 0,
+                dropped: 0,
+            },
+        };
+        (pool, shutdown)
     }
 }
 
-impl<S: Stream<Item=Address, Error=Void>> Future for Pool<S> {
+impl<S: Stream<Item=Vec<Peer>, Error=Void>> Future for Pool<S> {
     type Item = ();
     type Error = ();
     fn poll(&mut self) -> Result<Async<()>, ()> {
@@ -76,6 +189,7 @@ impl<S: Stream<Item=Address, Error=Void>> Future for Pool<S> {
             match self.update_addresses() {
                 Async::Ready(()) => {
                     info!("Eof on address stream, shutting down");
+                    self.channel.set_in_flight(self.in_flight());
                     return Ok(Async::Ready(()));
                 }
                 Async::NotReady => {}
@@ -84,8 +198,21 @@ impl<S: Stream<Item=Address, Error=Void>> Future for Pool<S> {
             self.check_pending();
             self.read_check();
             self.push_crowded();
+            self.report_metrics();
             self.new_metrics();
+            self.replay_spool();
             self.flush_metrics();
+            self.flush_retired();
+            if self.check_finished() {
+                let lost = self.channel.lost();
+                info!("Connection pool is shut down ({} metrics lost)",
+                      lost);
+                if let Some(finished) = self.finished.take() {
+                    finished.send(lost).ok();
+                }
+                self.stats.clear();
+                return Ok(Async::Ready(()));
+            }
             let ndeadline = self.calc_deadline();
             if ndeadline != self.deadline {
                 self.deadline = ndeadline;
@@ -101,11 +228,13 @@ impl<S: Stream<Item=Address, Error=Void>> Future for Pool<S> {
                 break;
             }
         }
+        self.update_stats();
+        self.channel.set_in_flight(self.in_flight());
         Ok(Async::NotReady)
     }
 }
 
-impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
+impl<S: Stream<Item=Vec<Peer>, Error=Void>> Pool<S> {
     fn update_addresses(&mut self) -> Async<()> {
         loop {
             let new_addr = match self.address_stream.poll() {
@@ -114,28 +243,47 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
                 Ok(Async::Ready(None)) => return Async::Ready(()),
                 Err(void) => unreachable(void),
             };
-            if let Some(ref mut old_addr) = self.cur_address {
+            if let Some(ref old_addr) = self.cur_addresses {
                 if old_addr != &new_addr {
-                    let (old, new) = old_addr.at(0)
-                                   .compare_addresses(&new_addr.at(0));
+                    let old = old_addr.iter()
+                        .filter(|a| !new_addr.contains(a))
+                        .cloned().collect::<Vec<_>>();
+                    let new = new_addr.iter()
+                        .filter(|a| !old_addr.contains(a))
+                        .cloned().collect::<Vec<_>>();
                     debug!("New addresss, to be retired {:?}, \
                             to be connected {:?}", old, new);
                     for _ in 0..self.pending.len() {
-                        let (addr, c) = self.pending.pop_front().unwrap();
+                        let (addr, dline, bk, c) = self.pending.pop_front()
+                            .unwrap();
                         // Drop pending connections to non-existing
                         // addresses
                         if !old.contains(&addr) {
-                            self.pending.push_back((addr, c));
+                            self.pending.push_back((addr, dline, bk, c));
                         } else {
                             debug!("Dropped pending {}", addr);
                         }
                     }
+                    for _ in 0..self.failed.len() {
+                        let (addr, time, bk) = self.failed.pop_front()
+                            .unwrap();
+                        // Don't reconnect to non-existing addresses
+                        if !old.contains(&addr) {
+                            self.failed.push_back((addr, time, bk));
+                        }
+                    }
+                    for addr in &old {
+                        if let Some(unsent) = self.retry.remove(addr) {
+                            self.channel.count_dropped(unsent.metrics());
+                        }
+                        self.reconnects.remove(addr);
+                    }
                     for _ in 0..self.normal.len() {
                         let (addr, c) = self.normal.pop_front().unwrap();
                         // Active connections are waiting to become idle
                         if old.contains(&addr) {
                             debug!("Retiring {}", addr);
-                            self.retired.push_back(c);
+                            self.retired.push_back((addr, c));
                         } else {
                             self.normal.push_back((addr, c));
                         }
@@ -145,57 +293,66 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
                         // Active connections are waiting to become idle
                         if old.contains(&addr) {
                             debug!("Retiring {}", addr);
-                            self.retired.push_back(c);
+                            self.retired.push_back((addr, c));
                         } else {
                             self.crowded.push_back((addr, c));
                         }
                     }
                     for addr in new {
-                        self.pending.push_back((addr, Box::new(
-                            // TODO(tailhook) timeout on connect
-                            TcpStream::connect(&addr, &self.handle)
-                            .map(|sock | Conn {
-                                io: IoBuf::new(sock),
-                                deadline: Instant::now()
-                                    // no data yet
-                                    + Duration::new(86400, 0),
-                            })
-                        )));
+                        self.connect(addr, 0);
                     }
                 }
             } else {
-                for addr in new_addr.at(0).addresses() {
-                    self.pending.push_back((addr, Box::new(
-                        // TODO(tailhook) timeout on connect
-                        TcpStream::connect(&addr, &self.handle)
-                        .map(|sock | Conn {
-                            io: IoBuf::new(sock),
-                            deadline: Instant::now()
-                                // no data yet
-                                + Duration::new(86400, 0),
-                        })
-                    )));
+                for addr in &new_addr {
+                    self.connect(addr.clone(), 0);
                 }
             }
+            self.ring = Ring::new(&new_addr);
+            self.cur_addresses = Some(new_addr);
         }
     }
+    fn connect(&mut self, addr: Peer, backoff: u64) {
+        let conn = Box::new(addr.connect(&self.config, &self.handle)
+            .map(move |sock| Conn {
+                io: IoBuf::new(sock),
+                deadline: Instant::now()
+                    // no data yet
+                    + Duration::new(86400, 0),
+                connected: Instant::now(),
+                backoff,
+                chunks: Chunks::default(),
+            }));
+        let deadline = Instant::now() + self.config.connect_timeout;
+        self.pending.push_back((addr, deadline, backoff, conn));
+    }
     fn check_pending(&mut self) {
+        let now = Instant::now();
         for _ in 0..self.pending.len() {
-            let (a, mut c) = self.pending.pop_front().unwrap();
+            let (a, dline, bk, mut c) = self.pending.pop_front().unwrap();
             match c.poll() {
-                Ok(Async::Ready(c)) => {
+                Ok(Async::Ready(mut c)) => {
                     // Can use it immediately
                     debug!("Connected {}", a);
+                    if let Some(unsent) = self.retry.remove(&a) {
+                        debug!("Resending {} bytes to {}",
+                            unsent.data.len(), a);
+                        c.io.out_buf.extend(&unsent.data);
+                        c.chunks.restore(unsent.chunks);
+                    }
                     self.normal.push_front((a, c));
                 }
+                Ok(Async::NotReady) if dline <= now => {
+                    warn!("Timeout connecting to {}", a);
+                    self.reconnect(a, bk);
+                }
                 Ok(Async::NotReady) => {
-                    self.pending.push_back((a, c));
+                    self.pending.push_back((a, dline, bk, c));
                 }
                 Err(e) => {
                     warn!("Can't establish connection to {}: {}", a, e);
                     // TODO(tailhook) set timer to reconnect
                     // Add to the end of the list
-                    self.reconnect(a);
+                    self.reconnect(a, bk);
                 }
             }
         }
@@ -205,14 +362,14 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
             let (a, mut c) = self.normal.pop_front().unwrap();
             if let Err(e) = c.io.read() {
                 warn!("Read error from {}: {}", a, e);
-                self.reconnect(a);
-            } else if c.io.in_buf.len() > 0 {
+                self.reconnect_conn(a, c);
+            } else if !c.io.in_buf.is_empty() {
                 warn!("Input data in carbon socket from {} (protocol error)",
                     a);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else if c.io.done() {
                 warn!("Connection from {} closed by peer", a);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else {
                 self.normal.push_back((a, c));
             }
@@ -221,33 +378,49 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
             let (a, mut c) = self.crowded.pop_front().unwrap();
             if let Err(e) = c.io.read() {
                 warn!("Read error from {}: {}", a, e);
-                self.reconnect(a);
-            } else if c.io.in_buf.len() > 0 {
+                self.reconnect_conn(a, c);
+            } else if !c.io.in_buf.is_empty() {
                 warn!("Input data in carbon socket from {} (protocol error)",
                     a);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else if c.io.done() {
                 warn!("Connection from {} closed by peer", a);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else {
                 self.crowded.push_back((a, c));
             }
         }
     }
-    fn reconnect(&mut self, addr: SocketAddr) {
-        let (min, max) = self.config.reconnect_delay;
-        let ms = thread_rng().gen_range(min, max);
+    fn reconnect_conn(&mut self, addr: Peer, mut conn: Conn<Connection>) {
+        let (unsent, lost) = conn.take_unsent(self.config.retry_buffer);
+        if lost > 0 {
+            self.channel.count_dropped(lost);
+        }
+        if !unsent.data.is_empty() {
+            if let Some(old) = self.retry.insert(addr.clone(), unsent) {
+                self.channel.count_dropped(old.metrics());
+            }
+        }
+        let backoff = conn.reconnect_backoff(&self.config);
+        self.reconnect(addr, backoff);
+    }
+    fn reconnect(&mut self, addr: Peer, backoff: u64) {
+        *self.reconnects.entry(addr.clone()).or_insert(0) += 1;
+        self.report.reconnects += 1;
+        let (backoff, ms) = next_delay(&self.config, backoff,
+                                       &mut thread_rng());
         self.failed.push_back((
             addr,
             Instant::now() + Duration::from_millis(ms),
+            backoff,
         ));
     }
     fn push_crowded(&mut self) {
         for _ in 0..self.crowded.len() {
             let (a, mut c) = self.crowded.pop_front().unwrap();
-            if let Err(e) = c.flush(&*self.config) {
+            if let Err(e) = c.flush(&self.config, &mut self.report.sent) {
                 warn!("Write error for {}: {}", a, e);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else if c.io.out_buf.len() < self.config.watermarks.0 {
                 self.normal.push_back((a, c));
             } else {
@@ -256,31 +429,183 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
         }
     }
     fn new_metrics(&mut self) {
-        if self.normal.len() == 0 {
+        if self.normal.is_empty() && self.crowded.is_empty() {
+            let now = Instant::now();
+            self.disconnected.get_or_insert(now);
+            let spool = self.spool_deadline().map(|dline| dline <= now)
+                .unwrap_or(false);
+            if spool || self.channel.shutdown_deadline().is_some() {
+                self.spool_metrics();
+            }
+            return;
+        }
+        self.disconnected = None;
+        if self.normal.is_empty() {
             // do not accept new metrics
             return;
         }
+        let mut metrics = Vec::new();
         while let Ok(Async::Ready(Some(metric))) = self.channel.poll() {
-            for &mut (_, ref mut c) in self.normal.iter_mut()
-                .chain(&mut self.crowded)
+            metrics.push(metric);
+        }
+        self.send_metrics(metrics);
+    }
+    fn send_metrics(&mut self, metrics: Vec<Metric>) {
+        let protocol = self.config.protocol;
+        let mut conns = self.normal.iter_mut()
+            .chain(&mut self.crowded)
+            .collect::<Vec<_>>();
+        let mut connections = vec![None; self.ring.nodes().len()];
+        for (idx, conn) in conns.iter().enumerate() {
+            if let Some(host) = self.ring.nodes().iter()
+                .position(|p| *p == conn.0)
             {
-                c.io.out_buf.extend(&metric.0);
+                connections[host] = Some(idx);
             }
         }
+        let hosts = Hosts::new(&self.ring, &connections);
+        let mut batches = conns.iter().map(|_| Vec::new())
+            .collect::<Vec<Vec<Metric>>>();
+        let mut held = HashMap::new();
+        let mut put = |host: usize, metric: Metric| {
+            let idx = match hosts.connection(host) {
+                Some(idx) => idx,
+                None => {
+                    held.entry(host).or_insert_with(Vec::new).push(metric);
+                    return;
+                }
+            };
+            batches[idx].push(metric);
+            if batches[idx].len() >= protocol.batch_size() {
+                let conn = &mut conns[idx].1;
+                conn.chunks.write_batch(protocol, &batches[idx],
+                                        &mut conn.io.out_buf);
+                batches[idx].clear();
+            }
+        };
+        let mut targets = Vec::new();
+        for metric in metrics {
+            targets.clear();
+            self.config.distribution.choose(metric.name(), &hosts,
+                                            &mut targets);
+            let (&last, others) = match targets.split_last() {
+                Some(x) => x,
+                None => {
+                    trace!("No host for metric {}, dropping",
+                        String::from_utf8_lossy(&metric.0));
+                    self.channel.count_dropped(1);
+                    continue;
+                }
+            };
+            for &host in others {
+                put(host, metric.clone());
+            }
+            put(last, metric);
+        }
+        for (batch, conn) in batches.iter().zip(conns.iter_mut()) {
+            if !batch.is_empty() {
+                let conn = &mut conn.1;
+                conn.chunks.write_batch(protocol, batch, &mut conn.io.out_buf);
+            }
+        }
+        for (host, metrics) in held {
+            let peer = self.ring.nodes()[host].clone();
+            self.hold_back(peer, &metrics);
+        }
+    }
+    /// Keeps metrics for the host which is not connected at the moment
+    ///
+    /// Metrics are sent when connection to the host is established. The
+    /// oldest ones are dropped if they don't fit `Config::retry_buffer`.
+    fn hold_back(&mut self, peer: Peer, metrics: &[Metric]) {
+        let protocol = self.config.protocol;
+        let limit = self.config.retry_buffer;
+        let mut lost = 0;
+        let empty = {
+            let unsent = self.retry.entry(peer.clone()).or_default();
+            for batch in metrics.chunks(protocol.batch_size()) {
+                lost += unsent.push(protocol, batch, limit);
+            }
+            unsent.data.is_empty()
+        };
+        if empty {
+            self.retry.remove(&peer);
+        }
+        if lost > 0 {
+            trace!("Retry buffer for {} is full, dropped {} metrics",
+                peer, lost);
+            self.channel.count_dropped(lost);
+        }
+    }
+    /// Moves metrics from the channel to the spool (if enabled)
+    fn spool_metrics(&mut self) {
+        let spool = match self.spool {
+            Some(ref mut spool) => spool,
+            None => return,
+        };
+        let mut dropped = 0;
+        while let Ok(Async::Ready(Some(metric))) = self.channel.poll() {
+            match spool.write(&metric) {
+                Ok(num) => dropped += num,
+                Err(e) => {
+                    error!("Can't write metric to spool: {}", e);
+                    dropped += 1;
+                }
+            }
+        }
+        if let Err(e) = spool.flush() {
+            error!("Can't write metrics to spool: {}", e);
+        }
+        if dropped > 0 {
+            self.channel.count_dropped(dropped);
+        }
+    }
+    /// Time when metrics start to be written to the spool
+    fn spool_deadline(&self) -> Option<Instant> {
+        match (&self.spool, self.disconnected) {
+            (&Some(_), Some(since)) => Some(since + self.config.spool_delay),
+            _ => None,
+        }
+    }
+    fn can_replay(&self) -> bool {
+        // only replay when connections keep up with the traffic
+        let spooled = match self.spool {
+            Some(ref spool) => !spool.is_empty(),
+            None => false,
+        };
+        spooled && !self.normal.is_empty() && self.crowded.is_empty()
+    }
+    fn replay_spool(&mut self) {
+        let now = Instant::now();
+        if !self.can_replay() || self.next_replay > now {
+            return;
+        }
+        self.next_replay = now + Duration::from_millis(REPLAY_INTERVAL);
+        let num = (self.config.spool_replay_rate as u64 * REPLAY_INTERVAL
+                   / 1000).max(1) as usize;
+        let metrics = match self.spool.as_mut().unwrap().read(num) {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                error!("Can't read metrics from spool: {}", e);
+                return;
+            }
+        };
+        debug!("Replaying {} metrics from spool", metrics.len());
+        self.send_metrics(metrics);
     }
     fn flush_metrics(&mut self) {
         // we're flushing only normal metrics, because crowded have already
         // been flushed at the start of poll
         for _ in 0..self.normal.len() {
             let (a, mut c) = self.normal.pop_front().unwrap();
-            if let Err(e) = c.flush(&*self.config) {
+            if let Err(e) = c.flush(&self.config, &mut self.report.sent) {
                 warn!("Write error for {}: {}", a, e);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else if c.io.out_buf.len() > self.config.watermarks.1 {
                 warn!("Buffer overflow for {}: {}/{}. \
-                    Dropping buffer and reconnecting... ", a,
+                    Reconnecting... ", a,
                     c.io.out_buf.len(), self.config.watermarks.1);
-                self.reconnect(a);
+                self.reconnect_conn(a, c);
             } else if c.io.out_buf.len() < self.config.watermarks.0 {
                 self.normal.push_back((a, c));
             } else {
@@ -288,33 +613,150 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
             }
         }
     }
+    fn flush_retired(&mut self) {
+        for _ in 0..self.retired.len() {
+            let (a, mut c) = self.retired.pop_front().unwrap();
+            if let Err(e) = c.flush(&self.config, &mut self.report.sent) {
+                warn!("Write error for retired {}: {}", a, e);
+                self.channel.count_dropped(c.chunks.metrics());
+            } else if c.io.out_buf.is_empty() {
+                debug!("Closing retired {}", a);
+            } else {
+                self.retired.push_back((a, c));
+            }
+        }
+    }
+    /// Submits metrics about the pool itself into the channel
+    fn report_metrics(&mut self) {
+        let config = self.config.clone();
+        let (prefix, interval) = match config.self_metrics {
+            Some((ref prefix, interval)) => (prefix, interval),
+            None => return,
+        };
+        let now = Instant::now();
+        if self.report.next > now {
+            return;
+        }
+        self.report.next = now + interval;
+        if self.channel.is_done() || self.channel.shutdown_deadline().is_some()
+        {
+            return;
+        }
+        let mut ts = Vec::with_capacity(20);
+        let precision = config.timestamp.for_protocol(config.protocol);
+        precision.write(&mut ts,
+            SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::new(0, 0)));
+        let ts = String::from_utf8_lossy(&ts);
+        let dropped = self.channel.dropped();
+        let buffered_bytes = self.normal.iter().chain(&self.crowded)
+            .chain(&self.retired)
+            .map(|(_, c)| c.io.out_buf.len())
+            .sum();
+        let values = [
+            ("sent", self.report.sent),
+            ("dropped", dropped - self.report.dropped),
+            ("reconnects", self.report.reconnects),
+            ("buffered_metrics", self.channel.buffered_metrics()),
+            ("buffered_bytes", buffered_bytes),
+            ("connections.normal", self.normal.len()),
+            ("connections.crowded", self.crowded.len()),
+            ("connections.pending", self.pending.len()),
+            ("connections.failed", self.failed.len()),
+            ("connections.retired", self.retired.len()),
+        ];
+        for &(name, value) in &values {
+            let line = if prefix.is_empty() {
+                format!("tk_carbon.{} {} {}\n", name, value, ts)
+            } else {
+                format!("{}.tk_carbon.{} {} {}\n", prefix, name, value, ts)
+            };
+            self.channel.push(Metric(line.into_bytes()));
+        }
+        self.report.sent = 0;
+        self.report.reconnects = 0;
+        self.report.dropped = dropped;
+    }
+    fn update_stats(&self) {
+        self.stats.update(|stats| {
+            let mut push = |state, addr: &Peer, buffered_bytes| {
+                stats.push(Entry {
+                    peer: Some(addr.clone()),
+                    state,
+                    buffered_bytes,
+                    reconnects: self.reconnects.get(addr).cloned()
+                        .unwrap_or(0),
+                })
+            };
+            for (a, c) in &self.normal {
+                push(ConnectionState::Normal, a, c.io.out_buf.len());
+            }
+            for (a, c) in &self.crowded {
+                push(ConnectionState::Crowded, a, c.io.out_buf.len());
+            }
+            for (a, c) in &self.retired {
+                push(ConnectionState::Retired, a, c.io.out_buf.len());
+            }
+            for (a, _, _, _) in &self.pending {
+                let unsent = self.retry.get(a).map(|u| u.data.len());
+                push(ConnectionState::Pending, a, unsent.unwrap_or(0));
+            }
+            for (a, _, _) in &self.failed {
+                let unsent = self.retry.get(a).map(|u| u.data.len());
+                push(ConnectionState::Failed, a, unsent.unwrap_or(0));
+            }
+        });
+    }
+    /// Returns true when all metrics are flushed or shutdown deadline passed
+    fn check_finished(&mut self) -> bool {
+        let flushed = self.channel.is_done() && self.retry.is_empty() &&
+            self.normal.iter().chain(&self.crowded).chain(&self.retired)
+                .all(|(_, c)| c.io.out_buf.is_empty());
+        if flushed {
+            return true;
+        }
+        match self.channel.shutdown_deadline() {
+            Some(deadline) if deadline <= Instant::now() => {
+                warn!("Shutdown deadline reached, dropping buffers");
+                self.channel.discard();
+                let lost = self.in_flight();
+                self.channel.count_dropped(lost);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Metrics received from the channel but not written to the network
+    fn in_flight(&self) -> usize {
+        self.normal.iter().chain(&self.crowded).chain(&self.retired)
+            .map(|(_, c)| c.chunks.metrics())
+            .chain(self.retry.values().map(|u| u.metrics()))
+            .sum()
+    }
     fn reconnect_failed(&mut self) {
         let now = Instant::now();
         for _ in 0..self.failed.len() {
-            let (addr, time) = self.failed.pop_front().unwrap();
+            let (addr, time, bk) = self.failed.pop_front().unwrap();
             if time <= now {
-                self.pending.push_back((addr, Box::new(
-                    // TODO(tailhook) timeout on connect
-                    TcpStream::connect(&addr, &self.handle)
-                    .map(move |sock| Conn {
-                        io: IoBuf::new(sock),
-                        deadline: now
-                            // no data yet
-                            + Duration::new(86400, 0),
-                    })
-                )));
+                self.connect(addr, bk);
             } else {
-                self.failed.push_back((addr, time));
+                self.failed.push_back((addr, time, bk));
             }
         }
     }
     fn calc_deadline(&mut self) -> Instant {
         // We assume that there are only few connections at any point in
         // time, so iterating is faster than keeping a heap of timers
-        self.failed.iter().map(|&(_, dline)| dline)
-        .chain(self.normal.iter().map(|&(_, ref c)| c.deadline))
-        .chain(self.crowded.iter().map(|&(_, ref c)| c.deadline))
-        // TODO(tailhook) make timeouts for pending connections
+        self.failed.iter().map(|&(_, dline, _)| dline)
+        .chain(self.pending.iter().map(|&(_, dline, _, _)| dline))
+        .chain(self.normal.iter().map(|(_, c)| c.deadline))
+        .chain(self.crowded.iter().map(|(_, c)| c.deadline))
+        .chain(self.retired.iter().map(|(_, c)| c.deadline))
+        .chain(if self.can_replay() { Some(self.next_replay) } else { None })
+        // spool deadline in the past means we're spooling already
+        .chain(self.spool_deadline().filter(|&dline| dline > Instant::now()))
+        .chain(self.channel.shutdown_deadline())
+        .chain(self.config.self_metrics.as_ref().map(|_| self.report.next))
         .min()
         // We can have all the queues empty, when we're waiting for address
         // to be resolved
@@ -323,11 +765,15 @@ impl<S: Stream<Item=Address, Error=Void>> Pool<S> {
 }
 
 impl<S: AsyncWrite> Conn<S> {
-    fn flush(&mut self, cfg: &Config) -> Result<(), io::Error> {
+    /// Flushes output buffer, adds number of metrics written to `sent`
+    fn flush(&mut self, cfg: &Config, sent: &mut usize)
+        -> Result<(), io::Error>
+    {
         let old_out = self.io.out_buf.len();
         if old_out > 0 {
             self.io.flush()?;
             let new_out = self.io.out_buf.len();
+            *sent += self.chunks.consumed(old_out - new_out);
             if new_out != old_out {
                 self.deadline = Instant::now() + cfg.write_timeout;
             } else {
@@ -339,3 +785,327 @@ impl<S: AsyncWrite> Conn<S> {
         Ok(())
     }
 }
+
+/// Returns new backoff state and the delay in milliseconds
+///
+/// Backoff state is zero for a fresh connection. For full jitter it's
+/// the upper bound of the delay, for decorrelated jitter it's the
+/// previous delay.
+fn next_delay<R: Rng>(config: &Config, backoff: u64, rng: &mut R)
+    -> (u64, u64)
+{
+    match config.reconnect_backoff {
+        None => {
+            let (min, max) = config.reconnect_delay;
+            (0, rng.gen_range(min, max))
+        }
+        Some(ref bk) => {
+            let ceil = if backoff == 0 {
+                bk.initial
+            } else {
+                bk.max.min((backoff as f64 * bk.multiplier) as u64)
+            };
+            match bk.jitter {
+                Jitter::Full => (ceil, rng.gen_range(0, ceil + 1)),
+                Jitter::Decorrelated => {
+                    let upper = ceil.max(bk.initial) + 1;
+                    let delay = rng.gen_range(bk.initial, upper);
+                    (delay, delay)
+                }
+            }
+        }
+    }
+}
+
+impl<S> Conn<S> {
+    /// Backoff state to reconnect with when connection fails
+    fn reconnect_backoff(&self, config: &Config) -> u64 {
+        if self.connected.elapsed() >= config.backoff_reset {
+            // connection was healthy for long enough, start from scratch
+            0
+        } else {
+            self.backoff
+        }
+    }
+    /// Returns whole chunks not written yet, up to `limit` bytes
+    ///
+    /// Also returns the number of metrics which are lost, i.e. partially
+    /// written or not fitting the limit.
+    fn take_unsent(&mut self, limit: usize) -> (Unsent, usize) {
+        let metrics = self.chunks.metrics();
+        let (mut chunks, written) = self.chunks.take();
+        let mut start = 0;
+        let mut lost = 0;
+        if written > 0 {
+            // partially written chunk can't be resent
+            let (size, num) = chunks.pop_front()
+                .expect("written bytes belong to a chunk");
+            start = size - written;
+            lost += num;
+        }
+        let mut total = self.io.out_buf.len() - start;
+        while total > limit {
+            let (size, num) = chunks.pop_front()
+                .expect("buffered bytes belong to a chunk");
+            start += size;
+            total -= size;
+            lost += num;
+        }
+        let data = self.io.out_buf[start..].to_vec();
+        self.io.out_buf.consume(self.io.out_buf.len());
+        (Unsent { data, chunks, metrics: metrics - lost }, lost)
+    }
+}
+
+impl Unsent {
+    fn metrics(&self) -> usize {
+        self.metrics
+    }
+    /// Appends a chunk, drops the oldest chunks not fitting the `limit`
+    ///
+    /// Returns the number of metrics dropped.
+    fn push(&mut self, protocol: Protocol, batch: &[Metric], limit: usize)
+        -> usize
+    {
+        let mut buf = Buf::new();
+        protocol.encode(batch, &mut buf);
+        self.data.extend_from_slice(&buf[..]);
+        self.chunks.push_back((buf.len(), batch.len()));
+        self.metrics += batch.len();
+        let mut start = 0;
+        let mut lost = 0;
+        while self.data.len() - start > limit {
+            let (size, num) = self.chunks.pop_front()
+                .expect("buffered bytes belong to a chunk");
+            start += size;
+            lost += num;
+        }
+        self.data.drain(..start);
+        self.metrics -= lost;
+        lost
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Instant, Duration};
+
+    use futures::{future, Future};
+    use rand::thread_rng;
+    use tk_bufstream::IoBuf;
+    use tokio_core::reactor::{Core, Timeout};
+    use void::Void;
+
+    use element::{Metric, Protocol, Chunks};
+    use peer::Peer;
+    use {Carbon, Config, Jitter};
+    use super::{Conn, Unsent, Pool, next_delay};
+
+    // each metric is 17 bytes
+    fn metric(name: &str) -> Metric {
+        Metric(format!("{} 1 1500000000\n", name).into_bytes())
+    }
+
+    /// Connection with every batch encoded and `written` bytes flushed
+    fn conn(batches: &[&[&str]], written: usize) -> Conn<()> {
+        let mut conn = Conn {
+            io: IoBuf::new(()),
+            deadline: Instant::now(),
+            connected: Instant::now(),
+            backoff: 0,
+            chunks: Chunks::default(),
+        };
+        for batch in batches {
+            let batch = batch.iter().map(|n| metric(n)).collect::<Vec<_>>();
+            conn.chunks.write_batch(Protocol::Plaintext, &batch,
+                                    &mut conn.io.out_buf);
+        }
+        conn.io.out_buf.consume(written);
+        conn.chunks.consumed(written);
+        conn
+    }
+
+    fn unsent(conn: &mut Conn<()>, limit: usize) -> (String, usize, usize) {
+        let (unsent, lost) = conn.take_unsent(limit);
+        assert!(conn.io.out_buf.is_empty());
+        assert_eq!(conn.chunks.metrics(), 0);
+        assert_eq!(unsent.chunks.iter().map(|&(size, _)| size).sum::<usize>(),
+                   unsent.data.len());
+        assert_eq!(unsent.chunks.iter().map(|&(_, num)| num).sum::<usize>(),
+                   unsent.metrics());
+        (String::from_utf8(unsent.data.clone()).unwrap(),
+         unsent.metrics(), lost)
+    }
+
+    #[test]
+    fn unsent_whole() {
+        let mut c = conn(&[&["a.1"], &["a.2", "a.3"]], 0);
+        assert_eq!(unsent(&mut c, 1 << 20), (
+            "a.1 1 1500000000\na.2 1 1500000000\na.3 1 1500000000\n".into(),
+            3, 0));
+    }
+
+    #[test]
+    fn unsent_partially_written() {
+        let mut c = conn(&[&["a.1"], &["a.2"], &["a.3"]], 5);
+        assert_eq!(unsent(&mut c, 1 << 20), (
+            "a.2 1 1500000000\na.3 1 1500000000\n".into(), 2, 1));
+        // first chunk is written, second one is partially written
+        let mut c = conn(&[&["a.1"], &["a.2", "a.3"], &["a.4"]], 20);
+        assert_eq!(unsent(&mut c, 1 << 20), (
+            "a.4 1 1500000000\n".into(), 1, 2));
+    }
+
+    #[test]
+    fn unsent_limit() {
+        let mut c = conn(&[&["a.1"], &["a.2"], &["a.3"]], 0);
+        assert_eq!(unsent(&mut c, 40), (
+            "a.2 1 1500000000\na.3 1 1500000000\n".into(), 2, 1));
+        // limit is smaller than a chunk
+        let mut c = conn(&[&["a.1"], &["a.2", "a.3"]], 0);
+        assert_eq!(unsent(&mut c, 20), ("".into(), 0, 3));
+        let mut c = conn(&[&["a.1", "a.2"], &["a.3"]], 3);
+        assert_eq!(unsent(&mut c, 16), ("".into(), 0, 3));
+    }
+
+    #[test]
+    fn unsent_disabled() {
+        // retry_buffer(0)
+        let mut c = conn(&[&["a.1"], &["a.2", "a.3"]], 0);
+        assert_eq!(unsent(&mut c, 0), ("".into(), 0, 3));
+        let mut c = conn(&[&["a.1"], &["a.2", "a.3"]], 10);
+        assert_eq!(unsent(&mut c, 0), ("".into(), 0, 3));
+        let mut c = conn(&[], 0);
+        assert_eq!(unsent(&mut c, 0), ("".into(), 0, 0));
+    }
+
+    #[test]
+    fn hold_back() {
+        let mut unsent = Unsent::default();
+        // each metric is 17 bytes, a batch is dropped as a whole
+        assert_eq!(unsent.push(Protocol::Plaintext,
+                               &[metric("a.1"), metric("a.2")], 40), 0);
+        assert_eq!(unsent.push(Protocol::Plaintext, &[metric("a.3")], 40), 2);
+        assert_eq!(unsent.push(Protocol::Plaintext, &[metric("a.4")], 40), 0);
+        assert_eq!(unsent.data, &b"a.3 1 1500000000\na.4 1 1500000000\n"[..]);
+        assert_eq!(unsent.metrics(), 2);
+        assert_eq!(unsent.push(Protocol::Plaintext, &[metric("a.5")], 0), 3);
+        assert!(unsent.data.is_empty());
+        assert_eq!(unsent.metrics(), 0);
+    }
+
+    #[test]
+    fn spool_while_pending() {
+        let dir = env::temp_dir()
+            .join(format!("tk-carbon-{}-pool-pending", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut core = Core::new().unwrap();
+        let (carbon, init) = Carbon::new(&Config::new()
+            .spool(&dir, 1 << 20)
+            .spool_delay(Duration::from_millis(100))
+            .done());
+        let addresses = future::empty::<Vec<Peer>, Void>().into_stream();
+        let (mut pool, _shutdown) = Pool::new(init, addresses,
+                                              &core.handle());
+        // connection which is never established (e.g. blackholed host)
+        pool.pending.push_back((Peer::Tcp("127.0.0.1:2003".parse().unwrap()),
+            Instant::now() + Duration::from_secs(100), 0,
+            Box::new(future::empty())));
+        core.handle().spawn(pool);
+        carbon.add_value_at("a.b", 1,
+            ::std::time::UNIX_EPOCH + Duration::from_secs(1500000000));
+        core.run(Timeout::new(Duration::from_millis(50), &core.handle())
+            .unwrap()).unwrap();
+        assert!(!dir.exists() || fs::read_dir(&dir).unwrap().all(|f| {
+            fs::read(f.unwrap().path()).unwrap().is_empty()
+        }));
+        core.run(Timeout::new(Duration::from_millis(200), &core.handle())
+            .unwrap()).unwrap();
+        let data = fs::read_dir(&dir).unwrap()
+            .map(|f| fs::read(f.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(data, vec![b"a.b 1 1500000000\n".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fixed_delay() {
+        let cfg = Config::new()
+            .reconnect_delay(Duration::from_millis(100)).done();
+        let mut rng = thread_rng();
+        for _ in 0..1000 {
+            let (backoff, delay) = next_delay(&cfg, 0, &mut rng);
+            assert_eq!(backoff, 0);
+            assert!((50..150).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn full_jitter() {
+        let cfg = Config::new()
+            .reconnect_backoff(Duration::from_millis(100),
+                Duration::from_millis(1000), 2., Jitter::Full)
+            .done();
+        let mut rng = thread_rng();
+        let mut backoff = 0;
+        let mut ceilings = Vec::new();
+        for _ in 0..7 {
+            let (next, delay) = next_delay(&cfg, backoff, &mut rng);
+            assert!(delay <= next);
+            ceilings.push(next);
+            backoff = next;
+        }
+        assert_eq!(ceilings, [100, 200, 400, 800, 1000, 1000, 1000]);
+        let (mut min, mut max) = (u64::MAX, 0);
+        for _ in 0..1000 {
+            let (next, delay) = next_delay(&cfg, 400, &mut rng);
+            assert_eq!(next, 800);
+            min = min.min(delay);
+            max = max.max(delay);
+        }
+        assert!(min < 100 && max > 700 && max <= 800, "{}..{}", min, max);
+    }
+
+    #[test]
+    fn decorrelated_jitter() {
+        let cfg = Config::new()
+            .reconnect_backoff(Duration::from_millis(100),
+                Duration::from_millis(1000), 3., Jitter::Decorrelated)
+            .done();
+        let mut rng = thread_rng();
+        let mut backoff = 0;
+        let mut max = 0;
+        for _ in 0..1000 {
+            let (next, delay) = next_delay(&cfg, backoff, &mut rng);
+            assert_eq!(next, delay);
+            let upper = if backoff == 0 {
+                100
+            } else {
+                (backoff * 3).min(1000)
+            };
+            assert!(delay >= 100 && delay <= upper,
+                "{} after {}", delay, backoff);
+            max = max.max(delay);
+            backoff = next;
+        }
+        assert!(max > 900, "{}", max);
+    }
+
+    #[test]
+    fn backoff_reset() {
+        let cfg = Config::new()
+            .reconnect_backoff(Duration::from_millis(100),
+                Duration::from_millis(1000), 2., Jitter::Full)
+            .backoff_reset(Duration::from_secs(60))
+            .done();
+        let mut c = conn(&[], 0);
+        c.backoff = 800;
+        assert_eq!(c.reconnect_backoff(&cfg), 800);
+        c.connected = Instant::now() - Duration::from_secs(61);
+        assert_eq!(c.reconnect_backoff(&cfg), 0);
+        assert_eq!(next_delay(&cfg, 0, &mut thread_rng()).0, 100);
+    }
+}
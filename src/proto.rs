@@ -2,11 +2,16 @@ use std::io;
 use std::sync::Arc;
 
 use futures::{Stream, Future, Async};
+use futures::sync::oneshot;
 use tk_bufstream::IoBuf;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_core::reactor::{Handle, Timeout};
 
 use channel::Receiver;
+use element::Chunks;
+use error::ProtoError;
+use shutdown::Shutdown;
+use stats::{Stats, Entry, ConnectionState};
 use {Init, Config};
 
 
@@ -17,6 +22,11 @@ pub struct Proto<T> {
     config: Arc<Config>,
     timeo: Timeout,
     handle: Handle,
+    chunks: Chunks,
+    shutdown_timeo: Option<Timeout>,
+    finished: Option<oneshot::Sender<usize>>,
+    shutdown_taken: bool,
+    stats: Stats,
 }
 
 impl Init {
@@ -27,68 +37,226 @@ impl Init {
         Proto {
             io: IoBuf::new(conn),
             channel: self.chan,
-            timeo: Timeout::new(self.config.write_timeout, &handle)
+            timeo: Timeout::new(self.config.write_timeout, handle)
                 .expect("can always set a timeout"),
             handle: handle.clone(),
             config: self.config,
+            chunks: Chunks::default(),
+            shutdown_timeo: None,
+            finished: None,
+            shutdown_taken: false,
+            stats: self.stats,
         }
     }
 }
 
 impl<T: AsyncRead+AsyncWrite> Future for Proto<T> {
     type Item = ();
-    type Error = ();
-    fn poll(&mut self) -> Result<Async<()>, ()> {
-        self.io.read().map_err(|_| ())?;
-        if self.io.in_buf.len() > 0 {
+    type Error = ProtoError;
+    fn poll(&mut self) -> Result<Async<()>, ProtoError> {
+        self.io.read().map_err(ProtoError::Read)?;
+        if !self.io.in_buf.is_empty() {
             // invalid protocol is an error
-            return Err(());
+            return Err(ProtoError::UnexpectedInput);
         }
         if self.io.done() {
             // connection closed by peer is just finish of a future
             return Ok(Async::Ready(()));
         }
-        if self.io.out_buf.len() >= self.config.watermarks.0 {
-            self.flush_output().map_err(|_| ())?;
-            if self.io.out_buf.len() >= self.config.watermarks.0 {
-                return Ok(Async::NotReady);
-            }
+        if self.check_shutdown_deadline().map_err(ProtoError::Timer)? {
+            return Ok(Async::Ready(()));
         }
-        while let Async::Ready(Some(metric)) = self.channel.poll()?  {
-            self.io.out_buf.extend(&metric.0);
+        loop {
             if self.io.out_buf.len() >= self.config.watermarks.0 {
+                self.flush_output()?;
+                if self.io.out_buf.len() >= self.config.watermarks.0 {
+                    self.update_stats();
+                    return Ok(Async::NotReady);
+                }
+            }
+            let protocol = self.config.protocol;
+            let mut batch = Vec::new();
+            let mut more = false;
+            while let Ok(Async::Ready(Some(metric))) = self.channel.poll() {
+                batch.push(metric);
+                if batch.len() >= protocol.batch_size() {
+                    self.chunks.write_batch(protocol, &batch,
+                                            &mut self.io.out_buf);
+                    batch.clear();
+                    if self.io.out_buf.len() >= self.config.watermarks.0 {
+                        more = true;
+                        break;
+                    }
+                }
+            }
+            if !batch.is_empty() {
+                self.chunks.write_batch(protocol, &batch,
+                                        &mut self.io.out_buf);
+            }
+            self.flush_output()?;
+            // channel isn't polled till the end, so we will not be woken up
+            // when there is more room in the buffer
+            if !more || self.io.out_buf.len() >= self.config.watermarks.0 {
                 break;
             }
         }
-        self.flush_output().map_err(|_| ())?;
-        if self.channel.is_done() && self.io.out_buf.len() == 0 {
+        if self.channel.is_done() && self.io.out_buf.is_empty() {
+            self.finish();
             return Ok(Async::Ready(()));
         }
-        return Ok(Async::NotReady);
+        self.update_stats();
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Proto<T> {
+    /// Returns a handle to gracefully shut down this connection
+    ///
+    /// Shutdown future resolves when this future finishes. Only a single
+    /// handle may be obtained, subsequent calls return `None`.
+    pub fn shutdown_handle(&mut self) -> Option<Shutdown> {
+        if self.shutdown_taken {
+            return None;
+        }
+        self.shutdown_taken = true;
+        let (shutdown, finished) = Shutdown::new(self.channel.control());
+        self.finished = Some(finished);
+        Some(shutdown)
+    }
+    fn finish(&mut self) {
+        if let Some(finished) = self.finished.take() {
+            finished.send(self.channel.lost()).ok();
+        }
+        self.stats.clear();
+    }
+    fn update_stats(&self) {
+        let state = if self.io.out_buf.len() >= self.config.watermarks.0 {
+            ConnectionState::Crowded
+        } else {
+            ConnectionState::Normal
+        };
+        self.stats.update(|stats| stats.push(Entry {
+            peer: None,
+            state,
+            buffered_bytes: self.io.out_buf.len(),
+            reconnects: 0,
+        }));
+    }
+    /// Returns true if shutdown deadline is reached and buffers are dropped
+    fn check_shutdown_deadline(&mut self) -> io::Result<bool> {
+        let deadline = match self.channel.shutdown_deadline() {
+            Some(deadline) => deadline,
+            None => return Ok(false),
+        };
+        if self.shutdown_timeo.is_none() {
+            self.shutdown_timeo = Some(Timeout::new_at(deadline,
+                                                       &self.handle)?);
+        }
+        let timeo = self.shutdown_timeo.as_mut().expect("timeout is set");
+        if timeo.poll()?.is_ready() {
+            warn!("Shutdown deadline reached, dropping buffers");
+            self.channel.discard();
+            let lost = self.chunks.metrics();
+            self.channel.count_dropped(lost);
+            self.finish();
+            return Ok(true);
+        }
+        Ok(false)
     }
 }
 
 impl<T: AsyncWrite> Proto<T> {
 
-    fn flush_output(&mut self) -> io::Result<()> {
+    fn flush_output(&mut self) -> Result<(), ProtoError> {
         let old_out = self.io.out_buf.len();
         if old_out > 0 {
-            self.io.flush()?;
+            // metrics in the buffer are lost if writing fails
+            self.channel.set_in_flight(self.chunks.metrics());
+            self.io.flush().map_err(ProtoError::Write)?;
             let new_out = self.io.out_buf.len();
+            self.chunks.consumed(old_out - new_out);
+            self.channel.set_in_flight(self.chunks.metrics());
             if new_out != old_out {
                 if new_out != 0 {
                     self.timeo = Timeout::new(
-                        self.config.write_timeout, &self.handle)?;
-                    self.timeo.poll()?;  // schedule a timeout
+                        self.config.write_timeout, &self.handle)
+                        .map_err(ProtoError::Timer)?;
+                    // schedule a timeout
+                    self.timeo.poll().map_err(ProtoError::Timer)?;
                 }
             } else {
-                let poll_result = self.timeo.poll()?;
+                let poll_result = self.timeo.poll()
+                    .map_err(ProtoError::Timer)?;
                 if poll_result.is_ready() {
                     // timeout, no byte is written within the period
-                    return Err(io::ErrorKind::TimedOut.into());
+                    return Err(ProtoError::WriteTimeout);
                 }
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+
+    use futures::{Future, Poll, Async};
+    use tokio_core::reactor::Core;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    use error::ProtoError;
+    use {Carbon, Config};
+
+    /// Connection which fails on every write
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::Other.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Broken {}
+
+    impl AsyncWrite for Broken {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn single_shutdown_handle() {
+        let core = Core::new().unwrap();
+        let (_carbon, init) = Carbon::new(&Config::new().done());
+        let mut proto = init.from_connection((), &core.handle());
+        assert!(proto.shutdown_handle().is_some());
+        assert!(proto.shutdown_handle().is_none());
+    }
+
+    #[test]
+    fn lost_on_failure() {
+        let mut core = Core::new().unwrap();
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let mut proto = init.from_connection(Broken, &core.handle());
+        let shutdown = proto.shutdown_handle().unwrap();
+        carbon.add_value("test.first", 1);
+        carbon.add_value("test.second", 2);
+        match core.run(proto) {
+            Err(ProtoError::Write(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // metrics were in the connection buffer when it failed
+        assert_eq!(shutdown.shutdown(Duration::from_secs(1)).wait(), Ok(2));
+    }
+}
@@ -3,38 +3,62 @@ use std::io::Write;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use futures::{Sink, StartSend, Poll, Async};
 use num_traits::Num;
 
+use config::Timestamp;
 use element::{Metric};
-use channel::{channel, Sender};
+use channel::{channel, Sender, Status};
+use error::Error;
+use handles::{Counter, Gauge, Timer};
+use stats::Stats;
 use {Init, Config};
 
 /// A structure that is used to submit values to carbon
 ///
 /// Internally it uses a state machine to communicate to the underlying
 /// network connection(s)
+///
+/// It also implements `Sink` that accepts pre-formatted metrics (see
+/// [`metric`](#method.metric)). Unlike `add_value`, the sink never drops
+/// metrics when the buffer is full but waits until there is room for them.
 #[derive(Clone)]
 pub struct Carbon {
     chan: Sender,
+    stats: Stats,
+    /// Prefix of every metric name including trailing dot
+    prefix: Arc<Vec<u8>>,
+    /// Tags added to every metric, each one starts with `;`
+    tags: Arc<Vec<u8>>,
+    timestamp: Timestamp,
 }
 
 impl Carbon {
     /// This creates an instance of the Carbon public interface and `Init`
     /// structure that can be used to initialize a Proto instance
     pub fn new(config: &Arc<Config>) -> (Carbon, Init) {
-        let (tx, rx) = channel(config.max_metrics_buffered);
-        return (
+        let (tx, rx) = channel(config);
+        let stats = Stats::new();
+        (
             Carbon {
                 chan: tx,
+                stats: stats.clone(),
+                prefix: Arc::new(Vec::new()),
+                tags: Arc::new(Vec::new()),
+                timestamp: config.timestamp.for_protocol(config.protocol),
             },
             Init {
                 chan: rx,
                 config: config.clone(),
+                stats,
             }
         )
     }
     /// Add any numeric value for carbon with current timestamp
     ///
+    /// Returned [`Status`](enum.Status.html) tells whether metric is queued
+    /// or dropped. It's fine to ignore it.
+    ///
     /// # Example
     ///
     /// ```ignore
@@ -48,10 +72,12 @@ impl Carbon {
     ///
     /// * When either name or value can't be formatted (Display'd)
     /// * When formatted name contains a whitespace or a newline
-    pub fn add_value<N, V>(&self, name:N, value: V)
+    /// * When this instance has tags (see `with_tags`) and name contains
+    ///   a semicolon
+    pub fn add_value<N, V>(&self, name:N, value: V) -> Status
         where N: Display, V: Num + Display
     {
-        self.add_value_at(name, value, SystemTime::now());
+        self.add_value_at(name, value, SystemTime::now())
     }
 
     /// Add any numeric value for carbon with specific timestamp
@@ -70,21 +96,455 @@ impl Carbon {
     ///
     /// * When either name or value can't be formatted (Display'd)
     /// * When formatted name contains a whitespace or a newline
+    /// * When this instance has tags (see `with_tags`) and name contains
+    ///   a semicolon
     /// * If timestamp is smaller than UNIX_EPOCH
     pub fn add_value_at<N, V>(&self, name: N, value: V, ts: SystemTime)
+        -> Status
         where N: Display, V: Num + Display
     {
         let mut buf = Vec::with_capacity(100);
-        let tm = ts.duration_since(UNIX_EPOCH)
-            .expect("time is larger than epoch");
-        writeln!(&mut buf, "{} {} {}", name, value, tm.as_secs())
-            .expect("writing to buffer always succeed");
-        assert!(buf.iter()
-            .filter(|&&x| x == b' ' || x == b'\n' || x == b'\n')
-            .count() == 3, // exactly two spaces and a newline at the end
-            "Metric should not contain any spaces or newlines inside");
-        self.chan.send(Metric(buf));
+        if let Err(e) = self.encode_scoped_name(&mut buf, name)
+            .and_then(|()| self.encode_value(&mut buf, value, ts))
+        {
+            panic!("Can't submit metric: {}", e);
+        }
+        self.chan.send(Metric(buf))
+    }
+
+    /// Add any numeric value for carbon with current timestamp
+    ///
+    /// This is a non-panicking version of
+    /// [`add_value`](#method.add_value).
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// if let Err(e) = carbon.try_add_value(
+    ///     format_args!("metrics.{}.cpu", user_input), 27)
+    /// {
+    ///     warn!("Can't submit metric: {}", e);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When either name or value can't be formatted (Display'd)
+    pub fn try_add_value<N, V>(&self, name: N, value: V)
+        -> Result<(), Error>
+        where N: Display, V: Num + Display
+    {
+        self.try_add_value_at(name, value, SystemTime::now())
+    }
+
+    /// Add any numeric value for carbon with specific timestamp
+    ///
+    /// This is a non-panicking version of
+    /// [`add_value_at`](#method.add_value_at). Dropped metric is also
+    /// reported as an error (`BufferFull` or `Disconnected`).
+    ///
+    /// # Panics
+    ///
+    /// * When either name or value can't be formatted (Display'd)
+    pub fn try_add_value_at<N, V>(&self, name: N, value: V, ts: SystemTime)
+        -> Result<(), Error>
+        where N: Display, V: Num + Display
+    {
+        self.metric_at(name, value, ts)
+            .and_then(|m| self.chan.send(m).into_result())
+    }
+
+    /// Format a metric with current timestamp to send it later
+    ///
+    /// This is useful for sending metrics via `Sink` interface, which
+    /// allows to wait for free space in the buffer instead of dropping
+    /// metrics.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let metric = carbon.metric("my.metric", 1)?;
+    /// handle.spawn(carbon.clone().send(metric)
+    ///     .map(|_| ()).map_err(|e| error!("Can't send metric: {}", e)));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When either name or value can't be formatted (Display'd)
+    pub fn metric<N, V>(&self, name: N, value: V) -> Result<Metric, Error>
+        where N: Display, V: Num + Display
+    {
+        self.metric_at(name, value, SystemTime::now())
+    }
+
+    /// Format a metric with specific timestamp to send it later
+    ///
+    /// # Panics
+    ///
+    /// * When either name or value can't be formatted (Display'd)
+    pub fn metric_at<N, V>(&self, name: N, value: V, ts: SystemTime)
+        -> Result<Metric, Error>
+        where N: Display, V: Num + Display
+    {
+        let mut buf = Vec::with_capacity(100);
+        self.encode_scoped_name(&mut buf, name)?;
+        self.encode_value(&mut buf, value, ts)?;
+        Ok(Metric(buf))
+    }
+
+    /// Add a value with graphite tags with current timestamp
+    ///
+    /// Tags are supported by graphite 1.1 and later. Metric is submitted
+    /// as `name;tag1=value1;tag2=value2`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// carbon.add_tagged_value("disk.used",
+    ///     vec![("host", "web1"), ("mount", "/srv")], 27)?;
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When either name, tag or value can't be formatted (Display'd)
+    pub fn add_tagged_value<N, I, K, T, V>(&self, name: N, tags: I, value: V)
+        -> Result<(), Error>
+        where N: Display, V: Num + Display,
+              I: IntoIterator<Item=(K, T)>, K: Display, T: Display,
+    {
+        self.add_tagged_value_at(name, tags, value, SystemTime::now())
+    }
+
+    /// Add a value with graphite tags with specific timestamp
+    ///
+    /// Returns an error if name or any of the tags contain characters
+    /// that are not allowed by graphite:
+    ///
+    /// * Name must be non-empty and must not contain `;`
+    /// * Tag name must be non-empty and must not contain any of `;!^=`
+    /// * Tag value must be non-empty, must not contain `;` and must not
+    ///   start with `~`
+    /// * None of them can contain whitespace
+    ///
+    /// Also returns an error if timestamp is smaller than UNIX_EPOCH or
+    /// metric is dropped (`BufferFull` or `Disconnected`).
+    ///
+    /// # Panics
+    ///
+    /// * When either name, tag or value can't be formatted (Display'd)
+    pub fn add_tagged_value_at<N, I, K, T, V>(&self, name: N, tags: I,
+        value: V, ts: SystemTime)
+        -> Result<(), Error>
+        where N: Display, V: Num + Display,
+              I: IntoIterator<Item=(K, T)>, K: Display, T: Display,
+    {
+        let mut buf = Vec::with_capacity(100);
+        buf.extend_from_slice(&self.prefix);
+        encode_tagged_name(&mut buf, name)?;
+        for (key, val) in tags {
+            encode_tag(&mut buf, key, val)?;
+        }
+        buf.extend_from_slice(&self.tags);
+        self.encode_value(&mut buf, value, ts)?;
+        self.chan.send(Metric(buf)).into_result()
+    }
+
+    /// Returns number of metrics dropped since this instance was created
+    ///
+    /// This counts metrics dropped because buffer is full or connection
+    /// is shut down, and also metrics dropped by the connection pool
+    /// (e.g. when retry buffer of the host metric is assigned to by
+    /// consistent hashing overflows). The counter is shared between all clones
+    /// of this instance.
+    pub fn dropped(&self) -> usize {
+        self.chan.dropped()
+    }
+
+    /// Returns a clone which prepends `prefix` to every metric name
+    ///
+    /// Dot is added between the prefix and metric name (unless prefix
+    /// already ends with a dot). Scopes may be nested, i.e.
+    /// `carbon.with_prefix("a")?.with_prefix("b")?` submits `a.b.name`.
+    /// Prefix is formatted and validated only once, so the clone is as fast
+    /// as original `Carbon` instance. Metric handles (`counter`, `gauge`,
+    /// `timer`) and `Aggregator` created from the clone use the prefix too.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let carbon = carbon.with_prefix(format_args!("{}.{}", service, host))?;
+    /// carbon.add_value("requests", 1);  // service.host.requests
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When prefix can't be formatted (Display'd)
+    pub fn with_prefix<P: Display>(&self, prefix: P) -> Result<Carbon, Error>
+    {
+        let mut buf = (*self.prefix).clone();
+        // tags might be added later, so `;` is never allowed in prefix
+        encode_tagged_name(&mut buf, prefix)?;
+        if !buf.ends_with(b".") {
+            buf.push(b'.');
+        }
+        Ok(Carbon {
+            prefix: Arc::new(buf),
+            .. self.clone()
+        })
+    }
+
+    /// Returns a clone which adds graphite tags to every metric
+    ///
+    /// Tags are appended after the tags passed to `add_tagged_value`.
+    /// Tags are added to the tags of the original instance when scopes
+    /// are nested. See `add_tagged_value_at` for restrictions on tags.
+    ///
+    /// Unlike the original instance, the clone doesn't accept metric names
+    /// which already contain tags (i.e. a semicolon), as they would be
+    /// mixed with the scope tags. `add_value` panics on such names and
+    /// `try_add_value` returns `Error::InvalidName`.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let carbon = carbon.with_tags(vec![("host", hostname)])?;
+    /// carbon.add_value("requests", 1);  // requests;host=web1
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When either tag name or value can't be formatted (Display'd)
+    pub fn with_tags<I, K, T>(&self, tags: I) -> Result<Carbon, Error>
+        where I: IntoIterator<Item=(K, T)>, K: Display, T: Display,
+    {
+        let mut buf = (*self.tags).clone();
+        for (key, val) in tags {
+            encode_tag(&mut buf, key, val)?;
+        }
+        Ok(Carbon {
+            tags: Arc::new(buf),
+            .. self.clone()
+        })
+    }
+
+    /// Encode metric name with prefix and tags of this instance
+    pub(crate) fn encode_scoped_name<N: Display>(&self, buf: &mut Vec<u8>,
+        name: N)
+        -> Result<(), Error>
+    {
+        self.encode_prefixed_name(buf, name)?;
+        buf.extend_from_slice(&self.tags);
+        Ok(())
+    }
+
+    /// Encode metric name with prefix, tags should be added by the caller
+    pub(crate) fn encode_prefixed_name<N: Display>(&self, buf: &mut Vec<u8>,
+        name: N)
+        -> Result<(), Error>
+    {
+        buf.extend_from_slice(&self.prefix);
+        if self.tags.is_empty() {
+            encode_name(buf, name)
+        } else {
+            encode_tagged_name(buf, name)
+        }
+    }
+
+    /// Encode value and timestamp with configured precision
+    pub(crate) fn encode_value<V>(&self, buf: &mut Vec<u8>, value: V,
+        ts: SystemTime)
+        -> Result<(), Error>
+        where V: Num + Display,
+    {
+        encode_value(buf, value, ts, self.timestamp)
+    }
+
+    /// Encoded tags of this instance (each one starts with `;`)
+    pub(crate) fn tags(&self) -> &[u8] {
+        &self.tags
+    }
+
+    /// Create a counter handle
+    ///
+    /// Name is formatted and validated only once, so submitting values via
+    /// handle is cheaper than `add_value`. Create a handle once and clone
+    /// it, as every handle counts its own total (see
+    /// [`Counter`](struct.Counter.html)).
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let requests = carbon.counter("my.requests");
+    /// requests.incr(1);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When name can't be formatted (Display'd)
+    /// * When formatted name contains a whitespace or a newline
+    /// * When this instance has tags (see `with_tags`) and name contains
+    ///   a semicolon
+    pub fn counter<N: Display>(&self, name: N) -> Counter {
+        Counter::new(self, name)
+    }
+
+    /// Create a gauge handle
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let queue_len = carbon.gauge("queue.len");
+    /// queue_len.set(queue.len());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When name can't be formatted (Display'd)
+    /// * When formatted name contains a whitespace or a newline
+    /// * When this instance has tags (see `with_tags`) and name contains
+    ///   a semicolon
+    pub fn gauge<N: Display>(&self, name: N) -> Gauge {
+        Gauge::new(self, name)
+    }
+
+    /// Create a timer handle
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let timer = carbon.timer("db.query");
+    /// timer.record(start.elapsed());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// * When name can't be formatted (Display'd)
+    /// * When formatted name contains a whitespace or a newline
+    /// * When this instance has tags (see `with_tags`) and name contains
+    ///   a semicolon
+    pub fn timer<N: Display>(&self, name: N) -> Timer {
+        Timer::new(self, name)
+    }
+
+    /// Submit already formatted metric, used by metric handles
+    pub(crate) fn send(&self, metric: Metric) -> Status {
+        self.chan.send(metric)
+    }
+    /// Submit already formatted metric without blocking, used by
+    /// aggregators which run on the tokio loop
+    pub(crate) fn try_send(&self, metric: Metric) -> Status {
+        self.chan.try_send(metric)
+    }
+
+    /// Returns a handle to get statistics of the underlying connection(s)
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+}
+
+impl Sink for Carbon {
+    type SinkItem = Metric;
+    type SinkError = Error;
+    fn start_send(&mut self, item: Metric) -> StartSend<Metric, Error> {
+        self.chan.start_send(item)
+    }
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        // metric is in the channel as soon as it's accepted
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Status {
+    fn into_result(self) -> Result<(), Error> {
+        match self {
+            Status::Submitted => Ok(()),
+            Status::BufferFull => Err(Error::BufferFull),
+            Status::Disconnected => Err(Error::Disconnected),
+        }
+    }
+}
+
+fn encode_name<N: Display>(buf: &mut Vec<u8>, name: N) -> Result<(), Error> {
+    let start = buf.len();
+    write!(buf, "{}", name)
+        .expect("writing to buffer always succeed");
+    if buf.len() == start || buf[start..].iter().any(|&x| is_space(x)) {
+        return Err(Error::InvalidName(lossy(&buf[start..])));
+    }
+    Ok(())
+}
+
+fn encode_tagged_name<N: Display>(buf: &mut Vec<u8>, name: N)
+    -> Result<(), Error>
+{
+    let start = buf.len();
+    encode_name(buf, name)?;
+    if buf[start..].contains(&b';') {
+        return Err(Error::InvalidName(lossy(&buf[start..])));
+    }
+    Ok(())
+}
+
+fn encode_tag<K, T>(buf: &mut Vec<u8>, key: K, val: T) -> Result<(), Error>
+    where K: Display, T: Display,
+{
+    buf.push(b';');
+    let start = buf.len();
+    write!(buf, "{}", key)
+        .expect("writing to buffer always succeed");
+    if !valid_tag_name(&buf[start..]) {
+        return Err(Error::InvalidTagName(lossy(&buf[start..])));
+    }
+    buf.push(b'=');
+    let start = buf.len();
+    write!(buf, "{}", val)
+        .expect("writing to buffer always succeed");
+    if !valid_tag_value(&buf[start..]) {
+        return Err(Error::InvalidTagValue(lossy(&buf[start..])));
+    }
+    Ok(())
+}
+
+fn encode_value<V>(buf: &mut Vec<u8>, value: V, ts: SystemTime,
+    precision: Timestamp)
+    -> Result<(), Error>
+    where V: Num + Display,
+{
+    let tm = ts.duration_since(UNIX_EPOCH).map_err(Error::BadTimestamp)?;
+    buf.push(b' ');
+    let start = buf.len();
+    write!(buf, "{}", value)
+        .expect("writing to buffer always succeed");
+    if !valid_value(&buf[start..]) {
+        return Err(Error::InvalidValue(lossy(&buf[start..])));
     }
+    buf.push(b' ');
+    precision.write(buf, tm);
+    buf.push(b'\n');
+    Ok(())
+}
+
+fn valid_value(value: &[u8]) -> bool {
+    !value.is_empty() && !value.iter().any(|&x| is_space(x))
+}
+
+fn is_space(x: u8) -> bool {
+    x == b' ' || x == b'\t' || x == b'\r' || x == b'\n'
+}
+
+fn valid_tag_name(name: &[u8]) -> bool {
+    !name.is_empty() && !name.iter().any(|&x| {
+        x == b';' || x == b'!' || x == b'^' || x == b'=' || is_space(x)
+    })
+}
+
+fn valid_tag_value(value: &[u8]) -> bool {
+    !value.is_empty() && value[0] != b'~' &&
+        !value.iter().any(|&x| x == b';' || is_space(x))
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
 }
 
 impl fmt::Debug for Carbon {
@@ -93,3 +553,265 @@ impl fmt::Debug for Carbon {
         write!(f, "Carbon({}/{})", a, b)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+    use std::num::ParseIntError;
+    use std::ops;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use futures::executor;
+    use num_traits::{Num, Zero, One};
+
+    use element::Metric;
+    use error::Error;
+    use {Carbon, Config, Protocol, Timestamp};
+    use super::{encode_tagged_name, valid_tag_name, valid_tag_value};
+
+    fn ts() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1500000000)
+    }
+
+    fn error<T>(result: Result<T, Error>) -> String {
+        match result {
+            Ok(_) => panic!("error expected"),
+            Err(e) => format!("{:?}", e),
+        }
+    }
+
+    fn text(metric: Result<Metric, Error>) -> String {
+        String::from_utf8(metric.unwrap().0).unwrap()
+    }
+
+    /// A number which is formatted with a space inside
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Spaced(u32);
+
+    impl fmt::Display for Spaced {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} 000", self.0)
+        }
+    }
+
+    macro_rules! spaced_ops {
+        ($($trait:ident $method:ident $op:tt),*) => {$(
+            impl ops::$trait for Spaced {
+                type Output = Spaced;
+                fn $method(self, other: Spaced) -> Spaced {
+                    Spaced(self.0 $op other.0)
+                }
+            }
+        )*}
+    }
+    spaced_ops!(Add add +, Sub sub -, Mul mul *, Div div /, Rem rem %);
+
+    impl Zero for Spaced {
+        fn zero() -> Spaced { Spaced(0) }
+        fn is_zero(&self) -> bool { self.0 == 0 }
+    }
+
+    impl One for Spaced {
+        fn one() -> Spaced { Spaced(1) }
+    }
+
+    impl Num for Spaced {
+        type FromStrRadixErr = ParseIntError;
+        fn from_str_radix(s: &str, radix: u32)
+            -> Result<Spaced, ParseIntError>
+        {
+            u32::from_str_radix(s, radix).map(Spaced)
+        }
+    }
+
+    #[test]
+    fn tag_name() {
+        assert!(valid_tag_name(b"host"));
+        assert!(valid_tag_name(b"a.b-c_d~"));
+        assert!(!valid_tag_name(b""));
+        for name in &["a;b", "a!b", "a^b", "a=b", "a b", "a\tb", "a\n"] {
+            assert!(!valid_tag_name(name.as_bytes()), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn tag_value() {
+        assert!(valid_tag_value(b"web1"));
+        assert!(valid_tag_value(b"/srv"));
+        assert!(valid_tag_value(b"a=b!^~"));
+        assert!(!valid_tag_value(b""));
+        for value in &["~web", "a;b", "a b", "a\tb", "a\r", "a\n"] {
+            assert!(!valid_tag_value(value.as_bytes()), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn tagged_name() {
+        let mut buf = b"prefix.".to_vec();
+        encode_tagged_name(&mut buf, "disk.used").unwrap();
+        assert_eq!(buf, b"prefix.disk.used");
+        assert_eq!(error(encode_tagged_name(&mut buf, "a;b")),
+                   r#"InvalidName("a;b")"#);
+        assert_eq!(error(encode_tagged_name(&mut Vec::new(), "")),
+                   r#"InvalidName("")"#);
+        assert_eq!(error(encode_tagged_name(&mut Vec::new(), "a b")),
+                   r#"InvalidName("a b")"#);
+    }
+
+    #[test]
+    fn add_tagged_value() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        carbon.add_tagged_value_at("disk.used",
+            vec![("host", "web1"), ("mount", "/srv")], 27, ts()).unwrap();
+        carbon.add_tagged_value_at("no.tags",
+            Vec::<(&str, &str)>::new(), 1.5, ts()).unwrap();
+        let mut chan = executor::spawn(init.chan);
+        assert_eq!(chan.wait_stream().unwrap().unwrap().0,
+                   &b"disk.used;host=web1;mount=/srv 27 1500000000\n"[..]);
+        assert_eq!(chan.wait_stream().unwrap().unwrap().0,
+                   &b"no.tags 1.5 1500000000\n"[..]);
+    }
+
+    #[test]
+    fn forbidden_chars() {
+        let (carbon, _init) = Carbon::new(&Config::new().done());
+        let check = |name, key, value| {
+            error(carbon.add_tagged_value_at(name,
+                vec![("host", "web1"), (key, value)], 1, ts()))
+        };
+        assert_eq!(check("a;b", "k", "v"), r#"InvalidName("a;b")"#);
+        assert_eq!(check("a\nb", "k", "v"), r#"InvalidName("a\nb")"#);
+        assert_eq!(check("", "k", "v"), r#"InvalidName("")"#);
+        assert_eq!(check("a", "", "v"), r#"InvalidTagName("")"#);
+        assert_eq!(check("a", "k=v", "v"), r#"InvalidTagName("k=v")"#);
+        assert_eq!(check("a", "k!", "v"), r#"InvalidTagName("k!")"#);
+        assert_eq!(check("a", "^k", "v"), r#"InvalidTagName("^k")"#);
+        assert_eq!(check("a", "k", ""), r#"InvalidTagValue("")"#);
+        assert_eq!(check("a", "k", "~v"), r#"InvalidTagValue("~v")"#);
+        assert_eq!(check("a", "k", "v;w"), r#"InvalidTagValue("v;w")"#);
+        assert_eq!(check("a", "k", "v w"), r#"InvalidTagValue("v w")"#);
+        assert_eq!(carbon.dropped(), 0);
+    }
+
+    #[test]
+    fn try_add_value_errors() {
+        let cfg = Config::new().max_metrics_buffered(1).done();
+        let (carbon, init) = Carbon::new(&cfg);
+        assert_eq!(error(carbon.try_add_value("a b", 1)),
+                   r#"InvalidName("a b")"#);
+        assert_eq!(error(carbon.try_add_value_at("", 1, ts())),
+                   r#"InvalidName("")"#);
+        assert_eq!(error(carbon.try_add_value("a", Spaced(1))),
+                   r#"InvalidValue("1 000")"#);
+        assert_eq!(error(carbon.try_add_value_at("a", Spaced(2), ts())),
+                   r#"InvalidValue("2 000")"#);
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert!(error(carbon.try_add_value_at("a", 1, before_epoch))
+                .starts_with("BadTimestamp("));
+        // invalid metrics are not counted as dropped
+        assert_eq!(carbon.dropped(), 0);
+        carbon.try_add_value_at("a", 1, ts()).unwrap();
+        assert_eq!(error(carbon.try_add_value("a", 2)), "BufferFull");
+        assert_eq!(error(carbon.try_add_value_at("a", 3, ts())),
+                   "BufferFull");
+        assert_eq!(carbon.dropped(), 2);
+        drop(init);
+        assert_eq!(error(carbon.try_add_value_at("a", 4, ts())),
+                   "Disconnected");
+    }
+
+    #[test]
+    fn timestamp() {
+        let ts = UNIX_EPOCH + Duration::new(1500000000, 123_456_789);
+        let check = |cfg: &mut Config| {
+            let (carbon, _init) = Carbon::new(&cfg.done());
+            String::from_utf8(carbon.metric_at("a", 1, ts).unwrap().0)
+                .unwrap()
+        };
+        assert_eq!(check(&mut Config::new()), "a 1 1500000000\n");
+        assert_eq!(check(Config::new().timestamp(Timestamp::Milliseconds)),
+                   "a 1 1500000000123\n");
+        assert_eq!(check(Config::new().timestamp(Timestamp::Fractional)),
+                   "a 1 1500000000.123456\n");
+        // pickle receivers expect seconds
+        assert_eq!(check(Config::new().protocol(Protocol::Pickle)
+                         .timestamp(Timestamp::Milliseconds)),
+                   "a 1 1500000000.123456\n");
+    }
+
+    #[test]
+    fn with_prefix() {
+        let (carbon, _init) = Carbon::new(&Config::new().done());
+        let a = carbon.with_prefix("a").unwrap();
+        assert_eq!(text(a.metric_at("x", 1, ts())), "a.x 1 1500000000\n");
+        let ab = a.with_prefix(format_args!("{}", "b")).unwrap();
+        assert_eq!(text(ab.metric_at("x", 1, ts())), "a.b.x 1 1500000000\n");
+        // original instances are not changed
+        assert_eq!(text(a.metric_at("x", 1, ts())), "a.x 1 1500000000\n");
+        assert_eq!(text(carbon.metric_at("x", 1, ts())), "x 1 1500000000\n");
+        let dot = carbon.with_prefix("a.b.").unwrap();
+        assert_eq!(text(dot.metric_at("x", 1, ts())), "a.b.x 1 1500000000\n");
+
+        assert_eq!(error(carbon.with_prefix("a;b")), r#"InvalidName("a;b")"#);
+        assert_eq!(error(a.with_prefix("")), r#"InvalidName("")"#);
+        assert_eq!(error(a.with_prefix("b c")), r#"InvalidName("b c")"#);
+    }
+
+    #[test]
+    fn with_tags() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let dc = carbon.with_tags(vec![("dc", "eu")]).unwrap();
+        assert_eq!(text(dc.metric_at("x", 1, ts())),
+                   "x;dc=eu 1 1500000000\n");
+        let both = dc.with_prefix("p").unwrap()
+            .with_tags(vec![("host", "web1")]).unwrap();
+        assert_eq!(text(both.metric_at("x", 1, ts())),
+                   "p.x;dc=eu;host=web1 1 1500000000\n");
+        // explicit tags go first
+        both.add_tagged_value_at("x", vec![("mount", "/srv")], 1, ts())
+            .unwrap();
+        let mut chan = executor::spawn(init.chan);
+        assert_eq!(chan.wait_stream().unwrap().unwrap().0,
+                   &b"p.x;mount=/srv;dc=eu;host=web1 1 1500000000\n"[..]);
+
+        // name with tags is only accepted without scope tags
+        assert_eq!(text(carbon.metric_at("x;k=v", 1, ts())),
+                   "x;k=v 1 1500000000\n");
+        assert_eq!(error(dc.metric_at("x;k=v", 1, ts())),
+                   r#"InvalidName("x;k=v")"#);
+        assert_eq!(error(dc.try_add_value_at("x;k=v", 1, ts())),
+                   r#"InvalidName("x;k=v")"#);
+        assert_eq!(error(carbon.with_tags(vec![("k", "")])),
+                   r#"InvalidTagValue("")"#);
+        assert_eq!(error(carbon.with_tags(vec![("k=", "v")])),
+                   r#"InvalidTagName("k=")"#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't submit metric")]
+    fn tagged_name_in_tagged_scope() {
+        let (carbon, _init) = Carbon::new(&Config::new().done());
+        carbon.with_tags(vec![("dc", "eu")]).unwrap()
+            .add_value("x;k=v", 1);
+    }
+
+    #[test]
+    fn scoped_handles() {
+        let (carbon, init) = Carbon::new(&Config::new().done());
+        let scoped = carbon.with_prefix("p").unwrap()
+            .with_tags(vec![("k", "v")]).unwrap();
+        scoped.counter("c").incr(2);
+        scoped.gauge("g").set(3);
+        scoped.timer("t").record(Duration::from_millis(5));
+        let mut chan = executor::spawn(init.chan);
+        let mut next = || {
+            let line = chan.wait_stream().unwrap().unwrap().0;
+            let line = String::from_utf8(line).unwrap();
+            // strip timestamp
+            line[..line.rfind(' ').unwrap()].to_string()
+        };
+        assert_eq!(next(), "p.c;k=v 2");
+        assert_eq!(next(), "p.g;k=v 3");
+        assert_eq!(next(), "p.t;k=v 5");
+    }
+}
@@ -0,0 +1,148 @@
+//! Consistent hashing ring compatible with carbon-relay (`carbon_ch`)
+
+use md5;
+
+use peer::Peer;
+
+
+const REPLICAS: usize = 100;
+
+
+pub struct Ring {
+    /// Sorted list of (position, node index)
+    ring: Vec<(u32, usize)>,
+    nodes: Vec<Peer>,
+}
+
+
+fn position(key: &[u8]) -> u32 {
+    let digest = md5::compute(key);
+    // first four hex digits of the md5 sum
+    (u32::from(digest[0]) << 8) | u32::from(digest[1])
+}
+
+fn node_key(peer: &Peer) -> String {
+    // carbon-relay uses python representation of (server, instance) tuple
+    match *peer {
+        Peer::Tcp(ref addr) => format!("('{}', None)", addr.ip()),
+        #[cfg(unix)]
+        Peer::Unix(ref path) => format!("('{}', None)", path.display()),
+    }
+}
+
+impl Ring {
+    pub fn new(nodes: &[Peer]) -> Ring {
+        let mut ring: Vec<(u32, usize)> = Vec::with_capacity(
+            nodes.len() * REPLICAS);
+        for (idx, node) in nodes.iter().enumerate() {
+            let key = node_key(node);
+            for i in 0..REPLICAS {
+                let mut pos = position(format!("{}:{}", key, i).as_bytes());
+                while ring.iter().any(|&(p, _)| p == pos) {
+                    pos += 1;
+                }
+                let at = ring.binary_search(&(pos, idx))
+                    .unwrap_or_else(|x| x);
+                ring.insert(at, (pos, idx));
+            }
+        }
+        Ring {
+            ring,
+            nodes: nodes.to_vec(),
+        }
+    }
+    /// All nodes in the order they were added
+    pub fn nodes(&self) -> &[Peer] {
+        &self.nodes
+    }
+    fn start(&self, name: &[u8]) -> usize {
+        let pos = position(name);
+        match self.ring.binary_search_by_key(&pos, |&(p, _)| p) {
+            Ok(idx) | Err(idx) => idx % self.ring.len(),
+        }
+    }
+    /// Returns index of the node responsible for the metric name
+    pub fn get_node(&self, name: &[u8]) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        Some(self.ring[self.start(name)].1)
+    }
+    /// Returns indexes of distinct nodes in the order of preference
+    ///
+    /// First node is the same one returned by `get_node`.
+    pub fn get_nodes(&self, name: &[u8]) -> Vec<usize> {
+        let mut result = Vec::with_capacity(self.nodes.len());
+        if self.ring.is_empty() {
+            return result;
+        }
+        let start = self.start(name);
+        for i in 0..self.ring.len() {
+            let node = self.ring[(start + i) % self.ring.len()].1;
+            if !result.contains(&node) {
+                result.push(node);
+                if result.len() == self.nodes.len() {
+                    break;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use peer::Peer;
+    use super::Ring;
+
+    fn ring() -> Ring {
+        Ring::new(&[
+            Peer::Tcp("10.0.0.1:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.2:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.3:2004".parse().unwrap()),
+            Peer::Tcp("10.0.0.4:2004".parse().unwrap()),
+        ])
+    }
+
+    fn nodes(ring: &Ring, name: &str) -> Vec<String> {
+        ring.get_nodes(name.as_bytes()).iter()
+            .map(|&idx| ring.nodes()[idx].to_string()).collect()
+    }
+
+    #[test]
+    fn empty() {
+        let ring = Ring::new(&[]);
+        assert!(ring.get_node(b"a.b").is_none());
+        assert!(ring.get_nodes(b"a.b").is_empty());
+    }
+
+    #[test]
+    fn carbon_relay_compatible() {
+        // expected values are produced by `ConsistentHashRing` of
+        // carbon-relay with nodes `('10.0.0.1', None)`...`('10.0.0.4', None)`
+        let ring = ring();
+        let expected = [
+            ("carbon.agents.host1.cpuUsage", ["2", "1", "3", "4"]),
+            ("servers.web1.cpu.user", ["3", "1", "2", "4"]),
+            ("servers.web2.cpu.user", ["3", "1", "2", "4"]),
+            ("servers.db1.disk.used", ["1", "4", "3", "2"]),
+            ("app.requests;host=web1", ["2", "4", "1", "3"]),
+            ("a", ["2", "4", "3", "1"]),
+            ("zzz", ["2", "1", "3", "4"]),
+        ];
+        for &(name, ref order) in &expected {
+            let order = order.iter()
+                .map(|n| format!("10.0.0.{}:2004", n))
+                .collect::<Vec<_>>();
+            let node = ring.get_node(name.as_bytes()).unwrap();
+            assert_eq!(ring.nodes()[node].to_string(), order[0], "{}", name);
+            assert_eq!(nodes(&ring, name), order, "{}", name);
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let ring = Ring::new(&[Peer::Tcp("10.0.0.1:2004".parse().unwrap())]);
+        assert_eq!(nodes(&ring, "a.b"), vec!["10.0.0.1:2004"]);
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::{Instant, Duration};
+
+use futures::{Future, Async};
+use futures::sync::oneshot;
+use void::Void;
+
+use channel::Control;
+
+
+/// A handle to gracefully shut down the connection (or connection pool)
+///
+/// Returned from `Init::connect_to` and `Proto::shutdown_handle`. Dropping
+/// the handle does nothing.
+pub struct Shutdown {
+    control: Control,
+    finished: oneshot::Receiver<usize>,
+}
+
+/// A future returned by `Shutdown::shutdown`
+///
+/// Resolves to the number of metrics lost during shutdown, i.e. metrics
+/// which were submitted before shutdown but were not written to the network
+/// before the deadline (or were dropped because no host is connected).
+pub struct ShutdownFuture {
+    control: Control,
+    finished: oneshot::Receiver<usize>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(control: Control) -> (Shutdown, oneshot::Sender<usize>)
+    {
+        let (tx, rx) = oneshot::channel();
+        (Shutdown { control, finished: rx }, tx)
+    }
+
+    /// Stop accepting metrics and wait until buffered ones are sent
+    ///
+    /// After this call all `Carbon` instances return
+    /// `Status::Disconnected` for new metrics. Metrics already buffered
+    /// are written to the connection(s), and the future resolves when
+    /// all of them are written into the sockets or when `timeout` passes,
+    /// whichever comes first.
+    pub fn shutdown(self, timeout: Duration) -> ShutdownFuture {
+        self.shutdown_at(Instant::now() + timeout)
+    }
+
+    /// Same as `shutdown` but with absolute deadline
+    pub fn shutdown_at(self, deadline: Instant) -> ShutdownFuture {
+        self.control.shutdown(deadline);
+        ShutdownFuture {
+            control: self.control,
+            finished: self.finished,
+        }
+    }
+}
+
+impl Future for ShutdownFuture {
+    type Item = usize;
+    type Error = Void;
+    fn poll(&mut self) -> Result<Async<usize>, Void> {
+        match self.finished.poll() {
+            Ok(Async::Ready(lost)) => Ok(Async::Ready(lost)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // connection future is dropped or failed, so everything left
+            // in the channel and in the connection buffers is lost
+            Err(oneshot::Canceled) => {
+                Ok(Async::Ready(self.control.buffered() +
+                                self.control.in_flight()))
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Disk-backed spool for metrics which can't be sent at the moment
+//!
+//! Metrics are stored in plaintext format in numbered segment files, so
+//! they are replayed in order and survive restart of the process.
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use element::Metric;
+
+
+const EXTENSION: &str = "spool";
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_size: u64,
+    segments: VecDeque<Segment>,
+    /// Total size of all segments
+    bytes: u64,
+    /// Writer of the last segment
+    writer: Option<BufWriter<File>>,
+    /// Reader of the first segment
+    reader: Option<BufReader<File>>,
+}
+
+struct Segment {
+    seq: u64,
+    bytes: u64,
+    metrics: usize,
+}
+
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64, segment_size: u64)
+        -> io::Result<Spool>
+    {
+        fs::create_dir_all(dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let seq = match path.file_stem().and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            {
+                Some(seq) => seq,
+                None => continue,
+            };
+            let mut segment = Segment { seq, bytes: 0, metrics: 0 };
+            let mut file = BufReader::new(File::open(&path)?);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let bytes = file.read_until(b'\n', &mut line)?;
+                if bytes == 0 {
+                    break;
+                }
+                segment.bytes += bytes as u64;
+                if line.ends_with(b"\n") {
+                    segment.metrics += 1;
+                }
+            }
+            segments.push(segment);
+        }
+        segments.sort_by_key(|s| s.seq);
+        if !segments.is_empty() {
+            info!("Spool {:?} contains {} metrics to replay", dir,
+                segments.iter().map(|s| s.metrics).sum::<usize>());
+        }
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            segment_size,
+            bytes: segments.iter().map(|s| s.bytes).sum(),
+            segments: segments.into_iter().collect(),
+            writer: None,
+            reader: None,
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, EXTENSION))
+    }
+    /// Removes the oldest segment, returns number of metrics in it
+    fn remove_first(&mut self) -> usize {
+        let segment = self.segments.pop_front()
+            .expect("remove_first is called on non-empty spool");
+        self.reader = None;
+        if self.segments.is_empty() {
+            self.writer = None;
+        }
+        self.bytes -= segment.bytes;
+        let path = self.path(segment.seq);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("Can't remove spool segment {:?}: {}", path, e);
+            }
+        }
+        segment.metrics
+    }
+    /// Writes metric to the spool
+    ///
+    /// Returns number of metrics dropped to fit the size limit (including
+    /// this one if it doesn't fit at all).
+    pub fn write(&mut self, metric: &Metric) -> io::Result<usize> {
+        let len = metric.0.len() as u64;
+        if len > self.max_bytes {
+            return Ok(1);
+        }
+        let mut dropped = 0;
+        while self.bytes + len > self.max_bytes {
+            dropped += self.remove_first();
+        }
+        if dropped > 0 {
+            warn!("Spool is full, dropped {} oldest metrics", dropped);
+        }
+        let rotate = match self.segments.back() {
+            Some(segment) => {
+                self.writer.is_none() || segment.bytes >= self.segment_size
+            }
+            None => true,
+        };
+        if rotate {
+            self.flush()?;
+            let seq = self.segments.back().map(|s| s.seq + 1).unwrap_or(0);
+            let file = File::create(self.path(seq))?;
+            self.writer = Some(BufWriter::new(file));
+            self.segments.push_back(Segment { seq, bytes: 0, metrics: 0 });
+        }
+        self.writer.as_mut().expect("writer is open")
+            .write_all(&metric.0)?;
+        let segment = self.segments.back_mut().expect("segment is created");
+        segment.bytes += len;
+        segment.metrics += 1;
+        self.bytes += len;
+        Ok(dropped)
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref mut writer) = self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+    /// Reads up to `max` oldest metrics from the spool
+    ///
+    /// Segment is removed when all metrics from it are read. Note: if
+    /// process is restarted in the middle of the segment, metrics from the
+    /// start of the segment are sent again.
+    pub fn read(&mut self, max: usize) -> io::Result<Vec<Metric>> {
+        if self.writer.is_some() {
+            // new segment is started on the next write
+            self.flush()?;
+            self.writer = None;
+        }
+        let mut result = Vec::new();
+        let mut line = Vec::new();
+        while result.len() < max && !self.segments.is_empty() {
+            if self.reader.is_none() {
+                let path = self.path(self.segments[0].seq);
+                match File::open(&path) {
+                    Ok(file) => self.reader = Some(BufReader::new(file)),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        warn!("Spool segment {:?} is removed", path);
+                        self.remove_first();
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            line.clear();
+            let bytes = self.reader.as_mut().expect("reader is open")
+                .read_until(b'\n', &mut line)?;
+            if bytes == 0 {
+                self.remove_first();
+                continue;
+            }
+            let segment = &mut self.segments[0];
+            segment.bytes = segment.bytes.saturating_sub(bytes as u64);
+            segment.metrics = segment.metrics.saturating_sub(1);
+            self.bytes = self.bytes.saturating_sub(bytes as u64);
+            // the last line might be truncated on crash
+            if line.ends_with(b"\n") {
+                result.push(Metric(line.clone()));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    use element::Metric;
+    use super::Spool;
+
+    /// Fresh directory for the test (removed by `cleanup`)
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("tk-carbon-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn cleanup(dir: PathBuf) {
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Metric of 17 bytes
+    fn metric(num: usize) -> Metric {
+        Metric(format!("m.{} 1 1500000000\n", num).into_bytes())
+    }
+
+    fn names(metrics: Vec<Metric>) -> Vec<String> {
+        metrics.iter()
+            .map(|m| String::from_utf8_lossy(m.name()).into_owned())
+            .collect()
+    }
+
+    fn files(dir: &PathBuf) -> Vec<String> {
+        let mut files = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn write(spool: &mut Spool, nums: ::std::ops::Range<usize>) -> usize {
+        nums.map(|num| spool.write(&metric(num)).unwrap()).sum()
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = dir("rotation");
+        let mut spool = Spool::open(&dir, 1000, 40).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(write(&mut spool, 0..5), 0);
+        spool.flush().unwrap();
+        assert_eq!(files(&dir), vec![
+            "00000000000000000000.spool",
+            "00000000000000000001.spool",
+        ]);
+        assert_eq!(fs::read(dir.join("00000000000000000001.spool")).unwrap(),
+            b"m.3 1 1500000000\nm.4 1 1500000000\n");
+        assert_eq!(names(spool.read(4).unwrap()),
+            vec!["m.0", "m.1", "m.2", "m.3"]);
+        assert_eq!(files(&dir), vec!["00000000000000000001.spool"]);
+        // write after read starts a new segment
+        assert_eq!(write(&mut spool, 5..6), 0);
+        spool.flush().unwrap();
+        assert_eq!(files(&dir), vec![
+            "00000000000000000001.spool",
+            "00000000000000000002.spool",
+        ]);
+        assert_eq!(names(spool.read(10).unwrap()), vec!["m.4", "m.5"]);
+        assert!(spool.is_empty());
+        assert_eq!(files(&dir), Vec::<String>::new());
+        cleanup(dir);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let dir = dir("drop_oldest");
+        let mut spool = Spool::open(&dir, 100, 40).unwrap();
+        assert_eq!(write(&mut spool, 0..5), 0);
+        // whole first segment is dropped
+        assert_eq!(spool.write(&metric(5)).unwrap(), 3);
+        // doesn't fit at all
+        let big = Metric(vec![b'x'; 101]);
+        assert_eq!(spool.write(&big).unwrap(), 1);
+        assert_eq!(names(spool.read(10).unwrap()), vec!["m.3", "m.4", "m.5"]);
+        cleanup(dir);
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = dir("reopen");
+        let mut spool = Spool::open(&dir, 1000, 40).unwrap();
+        assert_eq!(write(&mut spool, 0..5), 0);
+        spool.flush().unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 1000, 40).unwrap();
+        assert!(!spool.is_empty());
+        assert_eq!(names(spool.read(2).unwrap()), vec!["m.0", "m.1"]);
+        drop(spool);
+
+        // partially read segment is read again
+        let mut spool = Spool::open(&dir, 1000, 40).unwrap();
+        assert_eq!(write(&mut spool, 5..6), 0);
+        assert_eq!(names(spool.read(10).unwrap()),
+            vec!["m.0", "m.1", "m.2", "m.3", "m.4", "m.5"]);
+        assert!(spool.is_empty());
+        cleanup(dir);
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = dir("truncated");
+        let mut spool = Spool::open(&dir, 1000, 1000).unwrap();
+        assert_eq!(write(&mut spool, 0..2), 0);
+        spool.flush().unwrap();
+        drop(spool);
+        OpenOptions::new().append(true)
+            .open(dir.join("00000000000000000000.spool")).unwrap()
+            .write_all(b"m.2 1 15").unwrap();
+
+        let mut spool = Spool::open(&dir, 1000, 1000).unwrap();
+        assert_eq!(write(&mut spool, 3..4), 0);
+        assert_eq!(names(spool.read(10).unwrap()), vec!["m.0", "m.1", "m.3"]);
+        assert!(spool.is_empty());
+        cleanup(dir);
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use peer::Peer;
+
+
+/// State of the connection to a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connection is established and accepts metrics
+    Normal,
+    /// Connection is established but has more than low watermark bytes
+    /// buffered, so new metrics are not pulled from the channel
+    Crowded,
+    /// Connection is being established
+    Pending,
+    /// Address is removed from the name, connection is flushing its buffer
+    /// before closing
+    Retired,
+    /// Connection is broken, waiting for reconnect delay
+    Failed,
+}
+
+/// Statistics of a single connection
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    /// Address of the host (`None` for `Init::from_connection`)
+    pub address: Option<String>,
+    /// Current state of the connection
+    pub state: ConnectionState,
+    /// Bytes buffered in the connection and not written to the network yet
+    pub buffered_bytes: usize,
+    /// Number of times this address has been reconnected
+    pub reconnects: u64,
+}
+
+/// Statistics as stored by the connection
+///
+/// Addresses are only formatted when a snapshot is requested.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub peer: Option<Peer>,
+    pub state: ConnectionState,
+    pub buffered_bytes: usize,
+    pub reconnects: u64,
+}
+
+/// A handle to get connection statistics
+///
+/// Use [`Carbon::stats`](struct.Carbon.html#method.stats) to get one.
+/// Statistics are updated by the connection (or connection pool) every
+/// time it wakes up, so they might be slightly out of date.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    connections: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Stats {
+        Stats {
+            connections: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, Vec<Entry>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Replaces all entries with the ones pushed by `f`
+    ///
+    /// The vector is reused, so this doesn't allocate in the steady state.
+    pub(crate) fn update<F: FnOnce(&mut Vec<Entry>)>(&self, f: F) {
+        let mut connections = self.lock();
+        connections.clear();
+        f(&mut connections);
+    }
+    /// Removes all entries when connection (or pool) is finished
+    pub(crate) fn clear(&self) {
+        self.lock().clear();
+    }
+    /// Returns the statistics of every connection
+    ///
+    /// For connection pool there is an entry for every address the name
+    /// resolves to (and retired addresses which are still flushing).
+    pub fn snapshot(&self) -> Vec<ConnectionStats> {
+        self.lock().iter().map(|e| ConnectionStats {
+            address: e.peer.as_ref().map(|p| p.to_string()),
+            state: e.state,
+            buffered_bytes: e.buffered_bytes,
+            reconnects: e.reconnects,
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use peer::Peer;
+    use super::{Stats, Entry, ConnectionState};
+
+    #[test]
+    fn snapshot() {
+        let stats = Stats::new();
+        stats.update(|s| {
+            s.push(Entry {
+                peer: Some(Peer::Tcp("127.0.0.1:2003".parse().unwrap())),
+                state: ConnectionState::Crowded,
+                buffered_bytes: 100,
+                reconnects: 2,
+            });
+            s.push(Entry {
+                peer: None,
+                state: ConnectionState::Normal,
+                buffered_bytes: 0,
+                reconnects: 0,
+            });
+        });
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].address.as_ref().unwrap(), "127.0.0.1:2003");
+        assert_eq!(snapshot[0].state, ConnectionState::Crowded);
+        assert_eq!(snapshot[0].buffered_bytes, 100);
+        assert_eq!(snapshot[0].reconnects, 2);
+        assert_eq!(snapshot[1].address, None);
+        // previous entries are replaced
+        stats.update(|s| s.push(Entry {
+            peer: None,
+            state: ConnectionState::Failed,
+            buffered_bytes: 0,
+            reconnects: 1,
+        }));
+        assert_eq!(stats.snapshot().len(), 1);
+        stats.clear();
+        assert!(stats.snapshot().is_empty());
+    }
+}
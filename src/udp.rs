@@ -0,0 +1,158 @@
+use std::io;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use abstract_ns::Address;
+use futures::{Future, Async, Stream};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use void::{Void, unreachable};
+
+use channel::Receiver;
+use element::Metric;
+use {Init, Config};
+
+
+struct Udp<S> {
+    address_stream: S,
+    channel: Receiver,
+    config: Arc<Config>,
+    handle: Handle,
+
+    addresses: Vec<SocketAddr>,
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
+    /// Packet that is being filled with metrics
+    packet: Vec<u8>,
+    /// A metric that didn't fit into the previous packet
+    next_metric: Option<Metric>,
+    /// Packet ready to be sent and index of the next address to send it to
+    outgoing: Option<(Vec<u8>, usize)>,
+}
+
+
+impl Init {
+    /// Sends metrics to all the hosts using UDP
+    ///
+    /// Metrics are packed into datagrams up to `Config::udp_mtu` bytes.
+    /// A single metric is never split across datagrams. Only plaintext
+    /// protocol is supported over UDP, so `Config::protocol` is ignored.
+    ///
+    /// Note: there is no guarantee of delivery for UDP, so metrics may be
+    /// lost even when buffers are not full.
+    ///
+    /// This method spawns a future in the loop represented by handle.
+    /// The future exits when all references to API (`Carbon`
+    /// structure) are dropped and all buffers are flushed, or when address
+    /// stream is finished.
+    pub fn connect_udp<S>(self, address_stream: S, handle: &Handle)
+        where S: Stream<Item=Address, Error=Void> + 'static,
+    {
+        handle.spawn(Udp {
+            address_stream,
+            channel: self.chan,
+            config: self.config,
+            handle: handle.clone(),
+
+            addresses: Vec::new(),
+            socket_v4: None,
+            socket_v6: None,
+            packet: Vec::new(),
+            next_metric: None,
+            outgoing: None,
+        });
+    }
+}
+
+impl<S: Stream<Item=Address, Error=Void>> Future for Udp<S> {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Result<Async<()>, ()> {
+        if self.update_addresses().is_ready() {
+            info!("Eof on address stream, shutting down");
+            return Ok(Async::Ready(()));
+        }
+        if self.addresses.is_empty() {
+            // do not accept new metrics until address is resolved
+            return Ok(Async::NotReady);
+        }
+        loop {
+            if self.send_outgoing().is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            if let Some(metric) = self.next_metric.take() {
+                self.packet.extend(&metric.0);
+            }
+            while let Async::Ready(Some(metric)) = self.channel.poll()? {
+                if !self.packet.is_empty() &&
+                    self.packet.len() + metric.0.len() > self.config.udp_mtu
+                {
+                    self.next_metric = Some(metric);
+                    break;
+                }
+                self.packet.extend(&metric.0);
+            }
+            if self.packet.is_empty() {
+                break;
+            }
+            // Send packet even if it's not full, we don't want to delay
+            // metrics
+            let packet = self.packet.split_off(0);
+            self.outgoing = Some((packet, 0));
+        }
+        if self.channel.is_done() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<S: Stream<Item=Address, Error=Void>> Udp<S> {
+    fn update_addresses(&mut self) -> Async<()> {
+        loop {
+            let new_addr = match self.address_stream.poll() {
+                Ok(Async::Ready(Some(new_addr))) => new_addr,
+                Ok(Async::NotReady) => return Async::NotReady,
+                Ok(Async::Ready(None)) => return Async::Ready(()),
+                Err(void) => unreachable(void),
+            };
+            self.addresses = new_addr.at(0).addresses().collect();
+            debug!("New addresses {:?}", self.addresses);
+        }
+    }
+    fn send_outgoing(&mut self) -> Async<()> {
+        let (packet, mut idx) = match self.outgoing.take() {
+            Some(pair) => pair,
+            None => return Async::Ready(()),
+        };
+        while idx < self.addresses.len() {
+            let addr = self.addresses[idx];
+            match self.send_to(&packet, &addr) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.outgoing = Some((packet, idx));
+                    return Async::NotReady;
+                }
+                Err(e) => {
+                    warn!("Error sending metrics to {}: {}", addr, e);
+                }
+            }
+            idx += 1;
+        }
+        Async::Ready(())
+    }
+    fn send_to(&mut self, packet: &[u8], addr: &SocketAddr)
+        -> io::Result<usize>
+    {
+        let (socket, bind_ip) = if addr.is_ipv4() {
+            (&mut self.socket_v4, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        } else {
+            (&mut self.socket_v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        };
+        if socket.is_none() {
+            *socket = Some(UdpSocket::bind(
+                &SocketAddr::new(bind_ip, 0), &self.handle)?);
+        }
+        socket.as_ref().unwrap().send_to(packet, addr)
+    }
+}
@@ -0,0 +1,92 @@
+//! Connection pool against a local TLS server with self-signed certificate
+//!
+//! Certificate in `tests/tls` is generated by:
+//!
+//! ```sh
+//! openssl req -x509 -newkey rsa:2048 -nodes -days 36500 \
+//!     -subj /CN=localhost -addext "subjectAltName=DNS:localhost" \
+//!     -keyout key.pem -out cert.pem
+//! openssl pkcs12 -export -inkey key.pem -in cert.pem -passout pass:test \
+//!     -certpbe PBE-SHA1-3DES -keypbe PBE-SHA1-3DES -macalg sha1 \
+//!     -out identity.p12
+//! ```
+#![cfg(feature="tls")]
+
+extern crate abstract_ns;
+extern crate futures;
+extern crate native_tls;
+extern crate tk_carbon;
+extern crate tokio_core;
+extern crate void;
+
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, UNIX_EPOCH};
+
+use abstract_ns::Address;
+use futures::{future, stream, Stream, Future};
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use tk_carbon::{Carbon, Config};
+use tokio_core::reactor::Core;
+use void::Void;
+
+
+/// Accepts a single TLS connection and reads `lines` lines from it
+fn server(lines: usize) -> (SocketAddr, JoinHandle<Vec<String>>) {
+    let identity = Identity::from_pkcs12(
+        include_bytes!("tls/identity.p12"), "test").unwrap();
+    let acceptor = TlsAcceptor::new(identity).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let sock = match acceptor.accept(sock) {
+            Ok(sock) => sock,
+            Err(_) => return Vec::new(),
+        };
+        BufReader::new(sock).lines().take(lines)
+            .collect::<Result<_, _>>().unwrap()
+    });
+    (addr, thread)
+}
+
+/// Sends two metrics with TLS verified against `domain`
+///
+/// Returns number of metrics lost on shutdown.
+fn send(addr: SocketAddr, domain: &str) -> usize {
+    let connector = TlsConnector::builder()
+        .add_root_certificate(Certificate::from_pem(
+            include_bytes!("tls/cert.pem")).unwrap())
+        .build().unwrap();
+    let mut core = Core::new().unwrap();
+    let (carbon, init) = Carbon::new(&Config::new()
+        .tls(connector, domain)
+        .done());
+    let address: Address = vec![addr].into_iter().collect();
+    let shutdown = init.connect_to(
+        stream::iter_ok::<_, Void>(vec![address])
+            .chain(future::empty().into_stream()),
+        &core.handle());
+    let ts = UNIX_EPOCH + Duration::from_secs(1500000000);
+    carbon.add_value_at("test.first", 1, ts);
+    carbon.add_value_at("test.second", 2, ts);
+    core.run(shutdown.shutdown(Duration::from_secs(1))).unwrap()
+}
+
+#[test]
+fn self_signed() {
+    let (addr, server) = server(2);
+    assert_eq!(send(addr, "localhost"), 0);
+    assert_eq!(server.join().unwrap(), vec![
+        "test.first 1 1500000000",
+        "test.second 2 1500000000",
+    ]);
+}
+
+#[test]
+fn wrong_domain() {
+    let (addr, server) = server(2);
+    assert_eq!(send(addr, "carbon.example.com"), 2);
+    assert_eq!(server.join().unwrap(), Vec::<String>::new());
+}
@@ -0,0 +1,97 @@
+//! Plaintext metrics over UDP received by a local socket
+extern crate abstract_ns;
+extern crate futures;
+extern crate tk_carbon;
+extern crate tokio_core;
+extern crate void;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, UNIX_EPOCH};
+
+use abstract_ns::Address;
+use futures::{stream, Stream, Future};
+use tk_carbon::{Carbon, Config};
+use tokio_core::reactor::{Core, Timeout};
+use void::Void;
+
+
+fn receiver() -> (UdpSocket, SocketAddr) {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = sock.local_addr().unwrap();
+    (sock, addr)
+}
+
+/// Sends lines to all the addresses with the specified MTU
+fn send(lines: &[String], addresses: &[SocketAddr], mtu: usize) {
+    let mut core = Core::new().unwrap();
+    let (carbon, init) = Carbon::new(&Config::new().udp_mtu(mtu).done());
+    let address: Address = addresses.iter().cloned().collect();
+    init.connect_udp(
+        stream::iter_ok::<_, Void>(vec![address])
+            .chain(futures::future::empty().into_stream()),
+        &core.handle());
+    let ts = UNIX_EPOCH + Duration::from_secs(1500000000);
+    for line in lines {
+        let mut parts = line.split(' ');
+        let name = parts.next().unwrap();
+        let value: u64 = parts.next().unwrap().parse().unwrap();
+        carbon.add_value_at(name, value, ts);
+    }
+    core.run(Timeout::new(Duration::from_millis(100), &core.handle())
+        .unwrap()).unwrap();
+}
+
+/// Receives datagrams until `lines` lines are received
+fn receive(sock: &UdpSocket, lines: usize) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut received = 0;
+    let mut buf = [0u8; 65536];
+    while received < lines {
+        let len = sock.recv(&mut buf).unwrap();
+        let datagram = String::from_utf8(buf[..len].to_vec()).unwrap();
+        received += datagram.lines().count();
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+fn lines() -> Vec<String> {
+    let mut lines = (0..30)
+        .map(|i| format!("test.metric.{} {} 1500000000", i, i * 1000))
+        .collect::<Vec<_>>();
+    // doesn't fit into a single datagram
+    lines.insert(10, format!("test.{} 1 1500000000", "x".repeat(150)));
+    lines
+}
+
+#[test]
+fn packing() {
+    let (sock, addr) = receiver();
+    let lines = lines();
+    send(&lines, &[addr], 100);
+    let datagrams = receive(&sock, lines.len());
+    assert!(datagrams.len() > 5);
+    for datagram in &datagrams {
+        assert!(datagram.ends_with('\n'), "{:?}", datagram);
+        assert!(datagram.len() <= 100 || datagram.lines().count() == 1,
+            "{:?}", datagram);
+    }
+    // the oversized metric is sent in its own datagram
+    assert!(datagrams.iter().any(|d| d.len() > 150));
+    // lines are not split or reordered
+    let received = datagrams.iter().flat_map(|d| d.lines())
+        .collect::<Vec<_>>();
+    assert_eq!(received, lines);
+}
+
+#[test]
+fn fan_out() {
+    let (sock1, addr1) = receiver();
+    let (sock2, addr2) = receiver();
+    let lines = lines();
+    send(&lines, &[addr1, addr2], 1400);
+    let datagrams = receive(&sock1, lines.len());
+    assert_eq!(datagrams.concat(), lines.join("\n") + "\n");
+    assert_eq!(receive(&sock2, lines.len()), datagrams);
+}